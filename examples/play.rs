@@ -1,11 +1,15 @@
 use std::time::Duration;
 
-use kdmapi::KDMAPI;
+use kdmapi::{MidiMessage, KDMAPI, U4, U7};
 
 fn main() {
-    let kdmapi = KDMAPI.open_stream();
+    let kdmapi = KDMAPI.open_stream().unwrap();
 
-    kdmapi.send_direct_data(0x7F4090);
+    kdmapi.send(MidiMessage::NoteOn {
+        channel: U4::new(0).unwrap(),
+        key: U7::new(0x40).unwrap(),
+        velocity: U7::MAX,
+    });
 
     std::thread::sleep(Duration::from_secs(5));
 
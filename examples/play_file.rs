@@ -0,0 +1,23 @@
+use kdmapi::player::Player;
+use kdmapi::smf::Smf;
+use kdmapi::KDMAPI;
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let (stream, path) = match (args.next(), args.next()) {
+        (Some(flag), Some(path)) if flag == "--stream" => (true, path),
+        (Some(path), None) => (false, path),
+        _ => panic!("usage: play_file [--stream] <file.mid>"),
+    };
+    let kdmapi = KDMAPI.open_stream().unwrap();
+
+    let player = if stream {
+        Player::open_streaming(path, kdmapi).unwrap()
+    } else {
+        Player::new(&Smf::open(path).unwrap(), kdmapi)
+    };
+    player.play();
+    player.wait();
+
+    // player dropped, terminating the stream here
+}
@@ -0,0 +1,268 @@
+//! Stand-in for OmniMIDI that exports the KDMAPI functions and logs every
+//! call, so the bindings can be tested without the real driver.
+//!
+//! Calls are appended, one per line, to the file named by the
+//! `OMNIMIDI_STUB_LOG` environment variable. If `OMNIMIDI_STUB_FAIL_INIT` is
+//! set, `InitializeKDMAPIStream` fails.
+
+// Exported names and signatures mirror OmniMIDI's
+#![allow(non_snake_case, clippy::missing_safety_doc)]
+
+use std::collections::HashMap;
+use std::ffi::c_void;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+const MMSYSERR_NOERROR: u32 = 0;
+const MMSYSERR_INVALPARAM: u32 = 11;
+const MIDIERR_UNPREPARED: u32 = 64;
+const MHDR_PREPARED: u32 = 2;
+
+const CALLBACK_FUNCTION: u32 = 0x30000;
+const MOM_DONE: u32 = 0x3C9;
+
+const OM_SET: u32 = 0;
+const OM_GET: u32 = 1;
+
+static LOG_LOCK: Mutex<()> = Mutex::new(());
+static CALLBACK: AtomicUsize = AtomicUsize::new(0);
+static CALLBACK_INSTANCE: AtomicUsize = AtomicUsize::new(0);
+static START: Mutex<Option<Instant>> = Mutex::new(None);
+static SETTINGS: Mutex<Option<HashMap<u32, u32>>> = Mutex::new(None);
+
+#[repr(C)]
+pub struct DebugInfo {
+    rendering_time: f32,
+    active_voices: [u32; 16],
+    asio_input_latency: f64,
+    asio_output_latency: f64,
+}
+
+static DEBUG_INFO: DebugInfo = DebugInfo {
+    rendering_time: 12.5,
+    active_voices: [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+    asio_input_latency: 0.0,
+    asio_output_latency: 0.0,
+};
+
+#[repr(C)]
+pub struct MidiHdr {
+    data: *mut u8,
+    buffer_length: u32,
+    bytes_recorded: u32,
+    user: usize,
+    flags: u32,
+}
+
+fn log(line: &str) {
+    let _guard = LOG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(path) = std::env::var_os("OMNIMIDI_STUB_LOG") {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .expect("failed to open stub log");
+        writeln!(file, "{}", line).expect("failed to write stub log");
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn IsKDMAPIAvailable() -> bool {
+    log("IsKDMAPIAvailable");
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn InitializeKDMAPIStream() -> i32 {
+    log("InitializeKDMAPIStream");
+    if std::env::var_os("OMNIMIDI_STUB_FAIL_INIT").is_some() {
+        0
+    } else {
+        1
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn TerminateKDMAPIStream() -> i32 {
+    log("TerminateKDMAPIStream");
+    1
+}
+
+#[no_mangle]
+pub extern "C" fn ResetKDMAPIStream() {
+    log("ResetKDMAPIStream");
+}
+
+#[no_mangle]
+pub extern "C" fn SendDirectData(data: u32) -> u32 {
+    log(&format!("SendDirectData {:08x}", data));
+    MMSYSERR_NOERROR
+}
+
+#[no_mangle]
+pub extern "C" fn SendDirectDataNoBuf(data: u32) -> u32 {
+    log(&format!("SendDirectDataNoBuf {:08x}", data));
+    MMSYSERR_NOERROR
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn PrepareLongData(header: *mut MidiHdr, size: u32) -> u32 {
+    if header.is_null() || (size as usize) < std::mem::size_of::<MidiHdr>() {
+        return MMSYSERR_INVALPARAM;
+    }
+    log("PrepareLongData");
+    (*header).flags |= MHDR_PREPARED;
+    MMSYSERR_NOERROR
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn UnprepareLongData(header: *mut MidiHdr, size: u32) -> u32 {
+    if header.is_null() || (size as usize) < std::mem::size_of::<MidiHdr>() {
+        return MMSYSERR_INVALPARAM;
+    }
+    log("UnprepareLongData");
+    (*header).flags &= !MHDR_PREPARED;
+    MMSYSERR_NOERROR
+}
+
+unsafe fn send_long(function: &str, header: *mut MidiHdr, size: u32) -> u32 {
+    if header.is_null() || (size as usize) < std::mem::size_of::<MidiHdr>() {
+        return MMSYSERR_INVALPARAM;
+    }
+    let header = &*header;
+    if header.flags & MHDR_PREPARED == 0 {
+        return MIDIERR_UNPREPARED;
+    }
+    let data = std::slice::from_raw_parts(header.data, header.buffer_length as usize);
+    let hex: Vec<String> = data.iter().map(|b| format!("{:02x}", b)).collect();
+    log(&format!("{} {}", function, hex.join("")));
+    RunCallbackFunction(MOM_DONE, header as *const MidiHdr as usize, 0);
+    MMSYSERR_NOERROR
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn SendDirectLongData(header: *mut MidiHdr, size: u32) -> u32 {
+    send_long("SendDirectLongData", header, size)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn SendDirectLongDataNoBuf(header: *mut MidiHdr, size: u32) -> u32 {
+    send_long("SendDirectLongDataNoBuf", header, size)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ReturnKDMAPIVer(
+    major: *mut u32,
+    minor: *mut u32,
+    build: *mut u32,
+    revision: *mut u32,
+) -> bool {
+    *major = 4;
+    *minor = 1;
+    *build = 2;
+    *revision = 3;
+    true
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn DriverSettings(
+    setting: u32,
+    mode: u32,
+    value: *mut c_void,
+    size: u32,
+) -> bool {
+    if value.is_null() || size as usize != std::mem::size_of::<u32>() {
+        return false;
+    }
+    let value = value as *mut u32;
+    let mut settings = SETTINGS.lock().unwrap_or_else(|e| e.into_inner());
+    let settings = settings.get_or_insert_with(HashMap::new);
+    match mode {
+        OM_SET => {
+            log(&format!("DriverSettings set {:x} {}", setting, *value));
+            settings.insert(setting, *value);
+            true
+        }
+        OM_GET => {
+            log(&format!("DriverSettings get {:x}", setting));
+            *value = settings.get(&setting).copied().unwrap_or(0);
+            true
+        }
+        _ => false,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn GetDriverDebugInfo() -> *const DebugInfo {
+    &DEBUG_INFO
+}
+
+#[cfg(windows)]
+type WChar = u16;
+#[cfg(not(windows))]
+type WChar = u32;
+
+/// Logs the path and every line of the list
+#[no_mangle]
+pub unsafe extern "C" fn LoadCustomSoundFontsList(path: *const WChar) {
+    let mut len = 0;
+    while *path.add(len) != 0 {
+        len += 1;
+    }
+    let wide = std::slice::from_raw_parts(path, len);
+    #[cfg(windows)]
+    let path = String::from_utf16_lossy(wide);
+    #[cfg(not(windows))]
+    let path: String = wide
+        .iter()
+        .map(|&c| char::from_u32(c).unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect();
+
+    log(&format!("LoadCustomSoundFontsList {}", path));
+    for line in std::fs::read_to_string(&path).unwrap_or_default().lines() {
+        log(&format!("  {}", line));
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn timeGetTime64() -> u64 {
+    let mut start = START.lock().unwrap_or_else(|e| e.into_inner());
+    start.get_or_insert_with(Instant::now).elapsed().as_millis() as u64
+}
+
+type MidiOutProc = extern "system" fn(*mut c_void, u32, usize, usize, usize);
+
+#[no_mangle]
+pub extern "C" fn InitializeCallbackFeatures(
+    _handle: *mut c_void,
+    callback: usize,
+    instance: usize,
+    _user: usize,
+    mode: u32,
+) -> bool {
+    log(&format!("InitializeCallbackFeatures {:x}", mode));
+    if std::env::var_os("OMNIMIDI_STUB_FAIL_CALLBACK").is_some() {
+        return false;
+    }
+    let callback = if mode == CALLBACK_FUNCTION {
+        callback
+    } else {
+        0
+    };
+    CALLBACK_INSTANCE.store(instance, Ordering::SeqCst);
+    CALLBACK.store(callback, Ordering::SeqCst);
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn RunCallbackFunction(message: u32, param1: usize, param2: usize) {
+    let callback = CALLBACK.load(Ordering::SeqCst);
+    if callback != 0 {
+        let callback: MidiOutProc = unsafe { std::mem::transmute(callback) };
+        let instance = CALLBACK_INSTANCE.load(Ordering::SeqCst);
+        callback(std::ptr::null_mut(), message, instance, param1, param2);
+    }
+}
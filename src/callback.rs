@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use std::ffi::c_void;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use lazy_static::lazy_static;
+use libloading::Library;
+
+use crate::{load_optional_symbol, CallbackError, KDMAPIBinds};
+
+const CALLBACK_NULL: u32 = 0;
+const CALLBACK_FUNCTION: u32 = 0x30000;
+
+const MOM_OPEN: u32 = 0x3C7;
+const MOM_CLOSE: u32 = 0x3C8;
+const MOM_DONE: u32 = 0x3C9;
+
+type InitializeCallbackFeaturesFn =
+    unsafe extern "C" fn(*mut c_void, usize, usize, usize, u32) -> bool;
+type RunCallbackFunctionFn = unsafe extern "C" fn(u32, usize, usize);
+
+/// `InitializeCallbackFeatures` and `RunCallbackFunction`. The driver only
+/// calls back into a function installed by the first, and the second only
+/// reaches that function.
+#[derive(Clone, Copy)]
+pub(crate) struct CallbackFns {
+    initialize_callback_features: InitializeCallbackFeaturesFn,
+    run_callback_function: RunCallbackFunctionFn,
+}
+
+impl CallbackFns {
+    /// Returns `None` if either is missing, which makes
+    /// [`KDMAPIBinds::set_callback`] return [`CallbackError::Unsupported`]
+    pub(crate) fn load(lib: &Library) -> Option<Self> {
+        Some(CallbackFns {
+            initialize_callback_features: load_optional_symbol(lib, "InitializeCallbackFeatures")?,
+            run_callback_function: load_optional_symbol(lib, "RunCallbackFunction")?,
+        })
+    }
+
+    /// Identifies the library, as loading it again returns the same functions
+    fn key(&self) -> usize {
+        self.initialize_callback_features as *const () as usize
+    }
+}
+
+/// A WinMM style notification from the driver, see
+/// [`KDMAPIBinds::set_callback`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CallbackMessage {
+    /// `MOM_OPEN`
+    Open,
+    /// `MOM_CLOSE`
+    Close,
+    /// `MOM_DONE`, the driver is done with a long data buffer. Holds the
+    /// address of the buffer's `MIDIHDR`, see [`PendingSysEx::header`].
+    ///
+    /// [`PendingSysEx::header`]: crate::PendingSysEx::header
+    Done { header: usize },
+    /// Any other message, with its raw parameters
+    Other {
+        message: u32,
+        param1: usize,
+        param2: usize,
+    },
+}
+
+impl CallbackMessage {
+    fn from_raw(message: u32, param1: usize, param2: usize) -> Self {
+        match message {
+            MOM_OPEN => CallbackMessage::Open,
+            MOM_CLOSE => CallbackMessage::Close,
+            MOM_DONE => CallbackMessage::Done { header: param1 },
+            message => CallbackMessage::Other {
+                message,
+                param1,
+                param2,
+            },
+        }
+    }
+
+    fn to_raw(self) -> (u32, usize, usize) {
+        match self {
+            CallbackMessage::Open => (MOM_OPEN, 0, 0),
+            CallbackMessage::Close => (MOM_CLOSE, 0, 0),
+            CallbackMessage::Done { header } => (MOM_DONE, header, 0),
+            CallbackMessage::Other {
+                message,
+                param1,
+                param2,
+            } => (message, param1, param2),
+        }
+    }
+}
+
+type Callback = Arc<dyn Fn(CallbackMessage) + Send + Sync>;
+
+lazy_static! {
+    // Each library only keeps one callback, so neither do we. Callbacks are
+    // keyed by `CallbackFns::key`, which is passed to the driver as the
+    // instance data, and are looked up on every call rather than passed to
+    // the driver as a pointer, so notifications that race with unregistering
+    // can't reach a freed closure.
+    static ref CALLBACKS: Mutex<HashMap<usize, (u64, Callback)>> = Mutex::new(HashMap::new());
+}
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+// Held while changing the driver's callback, so that it always changes
+// together with ours. Separate from `CALLBACKS`, which is locked by
+// notifications the driver may send while the callback is changing.
+static REGISTRATION: Mutex<()> = Mutex::new(());
+
+fn callbacks() -> MutexGuard<'static, HashMap<usize, (u64, Callback)>> {
+    CALLBACKS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Has the signature of WinMM's `MidiOutProc`
+extern "system" fn midi_out_proc(
+    _handle: *mut c_void,
+    message: u32,
+    instance: usize,
+    param1: usize,
+    param2: usize,
+) {
+    // Not held while calling, so the callback can call back into the driver
+    let callback = callbacks().get(&instance).map(|(_, c)| c.clone());
+    if let Some(callback) = callback {
+        // Unwinding into the driver is undefined behavior
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+            callback(CallbackMessage::from_raw(message, param1, param2))
+        }));
+    }
+}
+
+impl KDMAPIBinds {
+    /// Calls `InitializeCallbackFeatures` so that the driver's notifications,
+    /// such as [`CallbackMessage::Done`] when it is done with a SysEx buffer,
+    /// are passed to `callback`
+    ///
+    /// `callback` may be called from the driver's threads, and must not
+    /// block for long. Notifications stop when the returned registration is
+    /// dropped. Registering another callback with the same library replaces
+    /// this one, once the driver has accepted it.
+    ///
+    /// Returns [`CallbackError::Unsupported`] if the driver doesn't export
+    /// the callback functions. If the driver rejects the callback, any
+    /// previous one stays registered.
+    pub fn set_callback<F>(&self, callback: F) -> Result<CallbackRegistration, CallbackError>
+    where
+        F: Fn(CallbackMessage) + Send + Sync + 'static,
+    {
+        let fns = self.callbacks.ok_or(CallbackError::Unsupported)?;
+        let _registering = REGISTRATION.lock().unwrap_or_else(|e| e.into_inner());
+
+        let ok = unsafe {
+            (fns.initialize_callback_features)(
+                ptr::null_mut(),
+                midi_out_proc as *const () as usize,
+                fns.key(),
+                0,
+                CALLBACK_FUNCTION,
+            )
+        };
+        if !ok {
+            return Err(CallbackError::InitFailed);
+        }
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        callbacks().insert(fns.key(), (id, Arc::new(callback)));
+        Ok(CallbackRegistration {
+            id,
+            binds: self.clone(),
+        })
+    }
+
+    /// Same as [`KDMAPIBinds::set_callback`], but sends notifications to the
+    /// returned channel
+    pub fn callback_channel(
+        &self,
+    ) -> Result<(CallbackRegistration, Receiver<CallbackMessage>), CallbackError> {
+        let (sender, receiver) = mpsc::channel();
+        let registration = self.set_callback(move |message| {
+            let _ = sender.send(message);
+        })?;
+        Ok((registration, receiver))
+    }
+
+    /// Calls `RunCallbackFunction`, which passes `message` to the registered
+    /// callback
+    pub fn run_callback(&self, message: CallbackMessage) -> Result<(), CallbackError> {
+        let fns = self.callbacks.ok_or(CallbackError::Unsupported)?;
+        let (message, param1, param2) = message.to_raw();
+        unsafe { (fns.run_callback_function)(message, param1, param2) };
+        Ok(())
+    }
+}
+
+/// A callback registered with [`KDMAPIBinds::set_callback`]
+///
+/// Unregisters the callback when dropped, unless it has been replaced.
+#[must_use = "the callback is unregistered when this is dropped"]
+pub struct CallbackRegistration {
+    id: u64,
+    binds: KDMAPIBinds,
+}
+
+impl Drop for CallbackRegistration {
+    fn drop(&mut self) {
+        let fns = match self.binds.callbacks {
+            Some(fns) => fns,
+            None => return,
+        };
+        let _registering = REGISTRATION.lock().unwrap_or_else(|e| e.into_inner());
+        let mut callbacks = callbacks();
+        if !matches!(callbacks.get(&fns.key()), Some((id, _)) if *id == self.id) {
+            return;
+        }
+        callbacks.remove(&fns.key());
+        drop(callbacks);
+        unsafe {
+            (fns.initialize_callback_features)(ptr::null_mut(), 0, 0, 0, CALLBACK_NULL);
+        }
+    }
+}
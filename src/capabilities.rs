@@ -0,0 +1,99 @@
+use std::ops::{BitAnd, BitOr, BitOrAssign};
+
+use crate::KDMAPIBinds;
+
+/// Set of optional KDMAPI features exported by the loaded driver
+///
+/// The core stream functions are always required, everything newer is
+/// optional so that older OmniMIDI builds can still be loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// `PrepareLongData`, `UnprepareLongData`, `SendDirectLongData` and
+    /// `SendDirectLongDataNoBuf`
+    pub const LONG_DATA: Capabilities = Capabilities(1 << 0);
+    /// `ReturnKDMAPIVer`
+    pub const VERSION: Capabilities = Capabilities(1 << 1);
+    /// `DriverSettings`
+    pub const DRIVER_SETTINGS: Capabilities = Capabilities(1 << 2);
+    /// `GetDriverDebugInfo`
+    pub const DEBUG_INFO: Capabilities = Capabilities(1 << 3);
+    /// `LoadCustomSoundFontsList`
+    pub const SOUNDFONT_LIST: Capabilities = Capabilities(1 << 4);
+    /// `timeGetTime64`
+    pub const TIMER: Capabilities = Capabilities(1 << 5);
+    /// `InitializeCallbackFeatures` and `RunCallbackFunction`
+    pub const CALLBACKS: Capabilities = Capabilities(1 << 6);
+
+    /// No optional features
+    pub const fn empty() -> Self {
+        Capabilities(0)
+    }
+
+    /// The raw bits of the set
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Returns true if every feature in `other` is also in `self`
+    pub const fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns true if the set has no features
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Capabilities(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for Capabilities {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl BitAnd for Capabilities {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self {
+        Capabilities(self.0 & rhs.0)
+    }
+}
+
+impl KDMAPIBinds {
+    /// Returns the optional features the loaded driver exports
+    pub fn capabilities(&self) -> Capabilities {
+        let mut caps = Capabilities::empty();
+        if self.long_data.is_some() {
+            caps |= Capabilities::LONG_DATA;
+        }
+        if self.return_kdmapi_ver.is_some() {
+            caps |= Capabilities::VERSION;
+        }
+        if self.driver_settings.is_some() {
+            caps |= Capabilities::DRIVER_SETTINGS;
+        }
+        if self.get_driver_debug_info.is_some() {
+            caps |= Capabilities::DEBUG_INFO;
+        }
+        if self.load_custom_soundfonts_list.is_some() {
+            caps |= Capabilities::SOUNDFONT_LIST;
+        }
+        if self.time_get_time_64.is_some() {
+            caps |= Capabilities::TIMER;
+        }
+        if self.callbacks.is_some() {
+            caps |= Capabilities::CALLBACKS;
+        }
+        caps
+    }
+}
@@ -0,0 +1,98 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::KDMAPIBinds;
+
+pub(crate) type TimeGetTime64Fn = unsafe extern "C" fn() -> u64;
+
+/// A monotonic time source for scheduling events
+///
+/// Only differences between times are meaningful.
+pub trait Clock: Send + Sync {
+    /// The time elapsed since a fixed point, such as the creation of the
+    /// clock
+    fn now(&self) -> Duration;
+}
+
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn now(&self) -> Duration {
+        (**self).now()
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for Box<C> {
+    fn now(&self) -> Duration {
+        (**self).now()
+    }
+}
+
+/// A [`Clock`] based on [`Instant`], used when the driver doesn't provide one
+#[derive(Debug, Clone, Copy)]
+pub struct InstantClock {
+    start: Instant,
+}
+
+impl InstantClock {
+    pub fn new() -> Self {
+        InstantClock {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for InstantClock {
+    fn default() -> Self {
+        InstantClock::new()
+    }
+}
+
+impl Clock for InstantClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+/// A [`Clock`] that calls the driver's `timeGetTime64`, so times line up with
+/// the driver's rendering timeline
+///
+/// Has a resolution of one millisecond.
+#[derive(Clone)]
+pub struct DriverClock {
+    // Keeps the library loaded
+    _binds: KDMAPIBinds,
+    time_get_time_64: TimeGetTime64Fn,
+}
+
+impl Clock for DriverClock {
+    fn now(&self) -> Duration {
+        Duration::from_millis(unsafe { (self.time_get_time_64)() })
+    }
+}
+
+impl KDMAPIBinds {
+    /// Calls `timeGetTime64`, returning the driver's time in milliseconds
+    ///
+    /// Returns `None` if the driver doesn't export it.
+    pub fn time_get_time_64(&self) -> Option<u64> {
+        let time_get_time_64 = self.time_get_time_64?;
+        Some(unsafe { time_get_time_64() })
+    }
+
+    /// Returns a [`DriverClock`], or `None` if the driver doesn't export
+    /// `timeGetTime64`
+    pub fn driver_clock(&self) -> Option<DriverClock> {
+        Some(DriverClock {
+            _binds: self.clone(),
+            time_get_time_64: self.time_get_time_64?,
+        })
+    }
+
+    /// Returns a [`DriverClock`] if the driver supports it, otherwise an
+    /// [`InstantClock`]
+    pub fn clock(&self) -> Box<dyn Clock> {
+        match self.driver_clock() {
+            Some(clock) => Box::new(clock),
+            None => Box::new(InstantClock::new()),
+        }
+    }
+}
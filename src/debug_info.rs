@@ -0,0 +1,151 @@
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::{KDMAPIBinds, KDMAPIStream};
+
+/// Mirror of OmniMIDI's `DebugInfo`
+#[repr(C)]
+pub(crate) struct RawDebugInfo {
+    rendering_time: f32,
+    active_voices: [u32; 16],
+    asio_input_latency: f64,
+    asio_output_latency: f64,
+}
+
+pub(crate) type GetDriverDebugInfoFn = unsafe extern "C" fn() -> *const RawDebugInfo;
+
+/// A snapshot of the driver's debug info, as returned by
+/// `GetDriverDebugInfo`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct DebugInfo {
+    /// The share of the audio buffer's time spent rendering, in percent.
+    /// Over 100 means the driver can't keep up.
+    pub rendering_time: f32,
+    /// Voices playing on each channel
+    pub active_voices: [u32; 16],
+    /// Input latency in milliseconds, only reported for ASIO output
+    pub asio_input_latency: f64,
+    /// Output latency in milliseconds, only reported for ASIO output
+    pub asio_output_latency: f64,
+}
+
+impl DebugInfo {
+    /// Voices playing on all channels
+    pub fn total_voices(&self) -> u32 {
+        self.active_voices.iter().sum()
+    }
+}
+
+impl KDMAPIBinds {
+    fn debug_info(&self) -> Option<DebugInfo> {
+        let get_driver_debug_info = self.get_driver_debug_info?;
+        let raw = unsafe { get_driver_debug_info() };
+        if raw.is_null() {
+            return None;
+        }
+        // The driver keeps updating the struct from its own threads, so read
+        // it in one go rather than through a reference
+        let raw = unsafe { std::ptr::read_volatile(raw) };
+        Some(DebugInfo {
+            rendering_time: raw.rendering_time,
+            active_voices: raw.active_voices,
+            asio_input_latency: raw.asio_input_latency,
+            asio_output_latency: raw.asio_output_latency,
+        })
+    }
+}
+
+/// Shortest interval accepted by [`KDMAPIStream::poll_debug_info`]
+pub const MIN_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+impl KDMAPIStream {
+    /// Calls `GetDriverDebugInfo` and copies the result
+    ///
+    /// Returns `None` if the driver doesn't export it. Values are updated by
+    /// the driver while they are copied, so they may not all come from the
+    /// same moment.
+    pub fn debug_info(&self) -> Option<DebugInfo> {
+        self.binds.debug_info()
+    }
+
+    /// Samples [`KDMAPIStream::debug_info`] every `interval` on a background
+    /// thread and passes each sample to `callback`, e.g. to drive voice count
+    /// and CPU meters.
+    ///
+    /// Sampling stops when the returned poller is dropped. Samples are
+    /// skipped while no stream is open, so the poller may outlive the stream.
+    ///
+    /// `interval` is raised to [`MIN_POLL_INTERVAL`] if shorter.
+    ///
+    /// Returns `None` if the driver doesn't export `GetDriverDebugInfo`.
+    pub fn poll_debug_info<F>(&self, interval: Duration, mut callback: F) -> Option<DebugInfoPoller>
+    where
+        F: FnMut(DebugInfo) + Send + 'static,
+    {
+        self.binds.get_driver_debug_info?;
+
+        let interval = interval.max(MIN_POLL_INTERVAL);
+        let binds = self.binds.clone();
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread_stop = stop.clone();
+        let thread = std::thread::Builder::new()
+            .name("kdmapi-debug-info".into())
+            .spawn(move || {
+                let (lock, changed) = &*thread_stop;
+                let mut next = Instant::now();
+                let mut stopped = lock.lock().unwrap_or_else(|e| e.into_inner());
+                while !*stopped {
+                    let now = Instant::now();
+                    if now < next {
+                        stopped = changed
+                            .wait_timeout(stopped, next - now)
+                            .unwrap_or_else(|e| e.into_inner())
+                            .0;
+                        continue;
+                    }
+                    // Don't hold the lock while a slow callback runs
+                    drop(stopped);
+                    if binds.is_stream_open.load(Ordering::Acquire) {
+                        if let Some(info) = binds.debug_info() {
+                            callback(info);
+                        }
+                    }
+                    stopped = lock.lock().unwrap_or_else(|e| e.into_inner());
+                    // Keep to the interval's grid so sampling doesn't drift,
+                    // skipping samples that were missed
+                    next += interval;
+                    if next < now {
+                        next = now + interval;
+                    }
+                }
+            })
+            .expect("failed to spawn debug info thread");
+
+        Some(DebugInfoPoller {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+/// Samples the driver's debug info in the background, see
+/// [`KDMAPIStream::poll_debug_info`]
+///
+/// Stops sampling when dropped.
+pub struct DebugInfoPoller {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for DebugInfoPoller {
+    fn drop(&mut self) {
+        let (stopped, changed) = &*self.stop;
+        *stopped.lock().unwrap_or_else(|e| e.into_inner()) = true;
+        changed.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
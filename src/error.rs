@@ -0,0 +1,294 @@
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::{fmt, io};
+
+use crate::DriverSetting;
+
+/// Errors that can occur while loading or using KDMAPI
+#[derive(Debug)]
+pub enum KdmapiError {
+    /// None of the candidate libraries could be loaded. Holds every
+    /// candidate that was tried along with the reason it failed.
+    LibraryNotFound(Vec<(OsString, libloading::Error)>),
+    /// The library was loaded, but a required KDMAPI export is missing
+    SymbolMissing {
+        name: &'static str,
+        source: libloading::Error,
+    },
+}
+
+impl fmt::Display for KdmapiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KdmapiError::LibraryNotFound(attempts) => {
+                write!(f, "failed to load OmniMIDI")?;
+                for (i, (name, e)) in attempts.iter().enumerate() {
+                    let sep = if i == 0 { ": " } else { "; " };
+                    write!(f, "{}`{}` ({})", sep, name.to_string_lossy(), e)?;
+                }
+                Ok(())
+            }
+            KdmapiError::SymbolMissing { name, .. } => {
+                write!(f, "OmniMIDI does not export `{}`", name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for KdmapiError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            KdmapiError::LibraryNotFound(attempts) => attempts
+                .last()
+                .map(|(_, e)| e as &(dyn std::error::Error + 'static)),
+            KdmapiError::SymbolMissing { source, .. } => Some(source),
+        }
+    }
+}
+
+/// Errors that can occur while opening a KDMAPI stream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamError {
+    /// A stream is already open on these bindings
+    AlreadyOpen,
+    /// `InitializeKDMAPIStream` failed, e.g. because the audio device is
+    /// busy. KDMAPI only reports success or failure, so there is no reason
+    /// to give. Opening can be retried later.
+    InitFailed,
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StreamError::AlreadyOpen => write!(f, "KDMAPI stream is already open"),
+            StreamError::InitFailed => write!(f, "failed to initialize KDMAPI stream"),
+        }
+    }
+}
+
+impl std::error::Error for StreamError {}
+
+/// Errors that can occur while sending a SysEx message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SysExError {
+    /// The message doesn't start with `F0` and end with `F7`
+    InvalidFraming,
+    /// The message contains a status byte between `F0` and `F7`
+    InvalidDataByte { index: usize, byte: u8 },
+    /// The message is too long to fit in a `MIDIHDR`
+    TooLong(usize),
+    /// The driver doesn't export the long data functions
+    Unsupported,
+    /// The driver still hadn't released the buffer after a second. The
+    /// buffer is leaked, so the driver can keep using it.
+    Timeout,
+    /// A KDMAPI long data function returned the given failure code
+    Driver { function: &'static str, code: u32 },
+}
+
+impl fmt::Display for SysExError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SysExError::InvalidFraming => {
+                write!(f, "SysEx message must start with F0 and end with F7")
+            }
+            SysExError::InvalidDataByte { index, byte } => {
+                write!(f, "SysEx message has status byte {:02X} at {}", byte, index)
+            }
+            SysExError::TooLong(len) => write!(f, "SysEx message is too long ({} bytes)", len),
+            SysExError::Unsupported => write!(f, "driver does not support long data"),
+            SysExError::Timeout => write!(f, "driver did not release the SysEx buffer"),
+            SysExError::Driver { function, code } => {
+                write!(f, "`{}` failed (returned {})", function, code)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SysExError {}
+
+/// Errors that can occur while registering or running a driver callback
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CallbackError {
+    /// The driver doesn't export `InitializeCallbackFeatures` and
+    /// `RunCallbackFunction`
+    Unsupported,
+    /// `InitializeCallbackFeatures` failed
+    InitFailed,
+}
+
+impl fmt::Display for CallbackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CallbackError::Unsupported => write!(f, "driver does not support callbacks"),
+            CallbackError::InitFailed => write!(f, "`InitializeCallbackFeatures` failed"),
+        }
+    }
+}
+
+impl std::error::Error for CallbackError {}
+
+/// Errors that can occur while reading or changing a driver setting
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DriverSettingError {
+    /// The driver doesn't export `DriverSettings`
+    Unsupported,
+    /// The value is outside of [`DriverSetting::range`]
+    OutOfRange { setting: DriverSetting, value: u32 },
+    /// `DriverSettings` failed for the given setting
+    Driver(DriverSetting),
+}
+
+impl fmt::Display for DriverSettingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DriverSettingError::Unsupported => write!(f, "driver does not support settings"),
+            DriverSettingError::OutOfRange { setting, value } => {
+                let range = setting.range();
+                write!(
+                    f,
+                    "{} is out of range for {:?} ({} to {})",
+                    value,
+                    setting,
+                    range.start(),
+                    range.end()
+                )
+            }
+            DriverSettingError::Driver(setting) => {
+                write!(f, "`DriverSettings` failed for {:?}", setting)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DriverSettingError {}
+
+/// Errors that can occur while loading SoundFonts
+#[derive(Debug)]
+pub enum SoundFontError {
+    /// The driver doesn't export `LoadCustomSoundFontsList`
+    Unsupported,
+    /// The path can't be passed to the driver or written to a list
+    InvalidPath(PathBuf),
+    Io(io::Error),
+}
+
+impl fmt::Display for SoundFontError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SoundFontError::Unsupported => {
+                write!(f, "driver does not support loading SoundFont lists")
+            }
+            SoundFontError::InvalidPath(path) => {
+                write!(f, "unsupported SoundFont path `{}`", path.display())
+            }
+            SoundFontError::Io(e) => write!(f, "failed to access SoundFont list: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SoundFontError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SoundFontError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SoundFontError {
+    fn from(e: io::Error) -> Self {
+        SoundFontError::Io(e)
+    }
+}
+
+/// Errors that can occur while decoding a MIDI short message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The status byte is a data byte or an undefined system status
+    InvalidStatus(u8),
+    /// The status byte is a SysEx or system common status, which can't be
+    /// represented as a [`MidiMessage`](crate::MidiMessage)
+    UnsupportedStatus(u8),
+    /// A data byte has its highest bit set
+    InvalidDataByte(u8),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::InvalidStatus(byte) => write!(f, "invalid status byte {:02X}", byte),
+            DecodeError::UnsupportedStatus(byte) => {
+                write!(f, "unsupported status byte {:02X}", byte)
+            }
+            DecodeError::InvalidDataByte(byte) => write!(f, "invalid data byte {:02X}", byte),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Errors that can occur while reading a Standard MIDI File
+#[derive(Debug)]
+pub enum SmfError {
+    Io(io::Error),
+    /// The data doesn't start with a valid `MThd` chunk
+    NotSmf,
+    /// The header has a format other than 0, 1 or 2
+    UnsupportedFormat(u16),
+    /// The header's division is 0 or uses an unknown SMPTE frame rate
+    InvalidTiming,
+    /// The data ended in the middle of a chunk header or event
+    UnexpectedEof,
+    /// A variable length quantity is longer than 4 bytes
+    InvalidVarLen,
+    /// A data byte appeared before any status byte
+    MissingStatus,
+    /// A status byte that can't appear in a track
+    InvalidStatus(u8),
+    /// A channel message is invalid
+    InvalidMessage(DecodeError),
+    /// A meta event of the given type is too short
+    InvalidMeta(u8),
+}
+
+impl fmt::Display for SmfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SmfError::Io(e) => write!(f, "failed to read MIDI file: {}", e),
+            SmfError::NotSmf => write!(f, "not a Standard MIDI File"),
+            SmfError::UnsupportedFormat(format) => {
+                write!(f, "unsupported MIDI file format {}", format)
+            }
+            SmfError::InvalidTiming => write!(f, "invalid MIDI file division"),
+            SmfError::UnexpectedEof => write!(f, "unexpected end of MIDI file"),
+            SmfError::InvalidVarLen => write!(f, "invalid variable length quantity"),
+            SmfError::MissingStatus => write!(f, "data byte without a running status"),
+            SmfError::InvalidStatus(status) => write!(f, "invalid status byte {:02X}", status),
+            SmfError::InvalidMessage(e) => write!(f, "invalid MIDI message: {}", e),
+            SmfError::InvalidMeta(kind) => write!(f, "invalid meta event {:02X}", kind),
+        }
+    }
+}
+
+impl std::error::Error for SmfError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SmfError::Io(e) => Some(e),
+            SmfError::InvalidMessage(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SmfError {
+    fn from(e: io::Error) -> Self {
+        SmfError::Io(e)
+    }
+}
+
+impl From<DecodeError> for SmfError {
+    fn from(e: DecodeError) -> Self {
+        SmfError::InvalidMessage(e)
+    }
+}
@@ -1,20 +1,97 @@
-use std::sync::atomic::AtomicBool;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
 
+use callback::CallbackFns;
+use clock::TimeGetTime64Fn;
+use debug_info::GetDriverDebugInfoFn;
 use lazy_static::lazy_static;
-use libloading::{Library, Symbol};
+use libloading::Library;
+use settings::DriverSettingsFn;
+use soundfont::LoadCustomSoundFontsListFn;
+use sysex::LongDataFns;
+
+mod callback;
+mod capabilities;
+mod clock;
+mod debug_info;
+mod error;
+mod loader;
+mod message;
+pub mod player;
+mod recording;
+mod settings;
+mod sink;
+pub mod smf;
+mod soundfont;
+mod sysex;
+pub mod tempo;
+mod version;
+
+pub use callback::{CallbackMessage, CallbackRegistration};
+pub use capabilities::Capabilities;
+pub use clock::{Clock, DriverClock, InstantClock};
+pub use debug_info::{DebugInfo, DebugInfoPoller, MIN_POLL_INTERVAL};
+pub use error::{
+    CallbackError, DecodeError, DriverSettingError, KdmapiError, SmfError, SoundFontError,
+    StreamError, SysExError,
+};
+pub use loader::{KDMAPILoader, DEFAULT_ENV_VAR, DEFAULT_LIBRARY_NAMES};
+pub use message::{MidiMessage, U14, U4, U7};
+pub use recording::{RecordedCall, RecordedEvent, RecordingSink};
+pub use settings::{DriverSetting, DriverSettings};
+pub use sink::MidiSink;
+pub use soundfont::{PresetRemap, SoundFontEntry, SoundFontList};
+pub use sysex::PendingSysEx;
+pub use version::KdmapiVersion;
 
 /// The dynamic bindings for KDMAPI
+///
+/// Owns a handle to the loaded library, which is unloaded once every clone of
+/// the bindings and every stream opened from them has been dropped. Clones
+/// share the library and the open stream state, as do bindings loaded
+/// separately from the same library, such as [`KDMAPI`] and the result of
+/// [`KDMAPIBinds::try_load`].
+///
+/// Only the core stream functions are required to load. Newer exports are
+/// optional, see [`KDMAPIBinds::capabilities`].
+#[derive(Clone)]
 pub struct KDMAPIBinds {
-    is_kdmapi_available: Symbol<'static, unsafe extern "C" fn() -> bool>,
-    initialize_kdmapi_stream: Symbol<'static, unsafe extern "C" fn() -> i32>,
-    terminate_kdmapi_stream: Symbol<'static, unsafe extern "C" fn() -> i32>,
-    reset_kdmapi_stream: Symbol<'static, unsafe extern "C" fn()>,
-    send_direct_data: Symbol<'static, unsafe extern "C" fn(u32) -> u32>,
-    send_direct_data_no_buf: Symbol<'static, unsafe extern "C" fn(u32) -> u32>,
-    is_stream_open: AtomicBool,
+    is_kdmapi_available: unsafe extern "C" fn() -> bool,
+    initialize_kdmapi_stream: unsafe extern "C" fn() -> i32,
+    terminate_kdmapi_stream: unsafe extern "C" fn() -> i32,
+    reset_kdmapi_stream: unsafe extern "C" fn(),
+    send_direct_data: unsafe extern "C" fn(u32) -> u32,
+    send_direct_data_no_buf: unsafe extern "C" fn(u32) -> u32,
+    long_data: Option<LongDataFns>,
+    return_kdmapi_ver: Option<unsafe extern "C" fn(*mut u32, *mut u32, *mut u32, *mut u32) -> bool>,
+    driver_settings: Option<DriverSettingsFn>,
+    get_driver_debug_info: Option<GetDriverDebugInfoFn>,
+    load_custom_soundfonts_list: Option<LoadCustomSoundFontsListFn>,
+    time_get_time_64: Option<TimeGetTime64Fn>,
+    callbacks: Option<CallbackFns>,
+    is_stream_open: Arc<AtomicBool>,
+    library_name: OsString,
+    // The function pointers above are only valid while this is alive. Only
+    // `None` for the function pointer backend used in tests.
+    _lib: Option<Arc<Library>>,
 }
 
 impl KDMAPIBinds {
+    /// Loads OmniMIDI and resolves every KDMAPI export, returning an error
+    /// instead of panicking if the library or one of the symbols is missing.
+    ///
+    /// Uses the default [`KDMAPILoader`] search order.
+    pub fn try_load() -> Result<KDMAPIBinds, KdmapiError> {
+        KDMAPILoader::new().load()
+    }
+
+    /// The candidate path or name that the library was loaded from
+    pub fn library_name(&self) -> &OsStr {
+        &self.library_name
+    }
+
     /// Calls `IsKDMAPIAvailable`
     pub fn is_kdmapi_available(&self) -> bool {
         unsafe { (self.is_kdmapi_available)() }
@@ -25,47 +102,98 @@ impl KDMAPIBinds {
     ///
     /// Automatically calls `TerminateKDMAPIStream` when dropped.
     ///
-    /// Errors if multiple streams are opened in parallel.
-    pub fn open_stream(&'static self) -> KDMAPIStream {
+    /// Returns [`StreamError::AlreadyOpen`] if a stream is already open on
+    /// these bindings or any of their clones. Only one caller can win the
+    /// race, so `InitializeKDMAPIStream` is never called twice concurrently.
+    ///
+    /// Returns [`StreamError::InitFailed`] if `InitializeKDMAPIStream` fails,
+    /// after which opening can be retried.
+    pub fn try_open_stream(&self) -> Result<KDMAPIStream, StreamError> {
         if self
             .is_stream_open
-            .load(std::sync::atomic::Ordering::Relaxed)
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
         {
-            panic!("KDMAPI stream is already open");
+            return Err(StreamError::AlreadyOpen);
         }
-        unsafe {
-            let result = (self.initialize_kdmapi_stream)();
-            if result == 0 {
-                panic!("Failed to initialize KDMAPI stream");
-            }
-            KDMAPIStream { binds: &self }
+        if unsafe { (self.initialize_kdmapi_stream)() } == 0 {
+            self.is_stream_open.store(false, Ordering::Release);
+            return Err(StreamError::InitFailed);
+        }
+        Ok(KDMAPIStream {
+            binds: self.clone(),
+        })
+    }
+
+    /// Same as [`KDMAPIBinds::try_open_stream`], but panics if
+    /// `InitializeKDMAPIStream` fails.
+    pub fn open_stream(&self) -> Result<KDMAPIStream, StreamError> {
+        match self.try_open_stream() {
+            Err(e @ StreamError::InitFailed) => panic!("{}", e),
+            result => result,
         }
     }
 }
 
-fn load_kdmapi_lib() -> Library {
-    unsafe { Library::new("OmniMIDI").unwrap() }
+fn load_symbol<T: Copy>(lib: &Library, name: &'static str) -> Result<T, KdmapiError> {
+    unsafe { lib.get::<T>(name.as_bytes()) }
+        .map(|symbol| *symbol)
+        .map_err(|source| KdmapiError::SymbolMissing { name, source })
 }
 
-fn load_kdmapi_binds(lib: &'static Library) -> KDMAPIBinds {
-    unsafe {
-        KDMAPIBinds {
-            is_kdmapi_available: lib.get(b"IsKDMAPIAvailable").unwrap(),
-            initialize_kdmapi_stream: lib.get(b"InitializeKDMAPIStream").unwrap(),
-            terminate_kdmapi_stream: lib.get(b"TerminateKDMAPIStream").unwrap(),
-            reset_kdmapi_stream: lib.get(b"ResetKDMAPIStream").unwrap(),
-            send_direct_data: lib.get(b"SendDirectData").unwrap(),
-            send_direct_data_no_buf: lib.get(b"SendDirectDataNoBuf").unwrap(),
-            is_stream_open: AtomicBool::new(false),
-        }
+fn load_optional_symbol<T: Copy>(lib: &Library, name: &'static str) -> Option<T> {
+    unsafe { lib.get::<T>(name.as_bytes()) }
+        .map(|symbol| *symbol)
+        .ok()
+}
+
+lazy_static! {
+    /// The open stream flag of every loaded library, keyed by the address of
+    /// its `InitializeKDMAPIStream`. Loading a library again returns the same
+    /// OS handle, and OmniMIDI only has one stream per process.
+    static ref STREAM_GUARDS: Mutex<HashMap<usize, Weak<AtomicBool>>> =
+        Mutex::new(HashMap::new());
+}
+
+fn stream_guard(initialize_kdmapi_stream: unsafe extern "C" fn() -> i32) -> Arc<AtomicBool> {
+    let mut guards = STREAM_GUARDS.lock().unwrap_or_else(|e| e.into_inner());
+    let key = initialize_kdmapi_stream as *const () as usize;
+    if let Some(guard) = guards.get(&key).and_then(Weak::upgrade) {
+        return guard;
     }
+    guards.retain(|_, guard| guard.strong_count() > 0);
+    let guard = Arc::new(AtomicBool::new(false));
+    guards.insert(key, Arc::downgrade(&guard));
+    guard
+}
+
+fn load_kdmapi_binds(lib: Library, library_name: OsString) -> Result<KDMAPIBinds, KdmapiError> {
+    let initialize_kdmapi_stream = load_symbol(&lib, "InitializeKDMAPIStream")?;
+    Ok(KDMAPIBinds {
+        is_kdmapi_available: load_symbol(&lib, "IsKDMAPIAvailable")?,
+        initialize_kdmapi_stream,
+        terminate_kdmapi_stream: load_symbol(&lib, "TerminateKDMAPIStream")?,
+        reset_kdmapi_stream: load_symbol(&lib, "ResetKDMAPIStream")?,
+        send_direct_data: load_symbol(&lib, "SendDirectData")?,
+        send_direct_data_no_buf: load_symbol(&lib, "SendDirectDataNoBuf")?,
+        long_data: LongDataFns::load(&lib),
+        return_kdmapi_ver: load_optional_symbol(&lib, "ReturnKDMAPIVer"),
+        driver_settings: load_optional_symbol(&lib, "DriverSettings"),
+        get_driver_debug_info: load_optional_symbol(&lib, "GetDriverDebugInfo"),
+        load_custom_soundfonts_list: load_optional_symbol(&lib, "LoadCustomSoundFontsList"),
+        time_get_time_64: load_optional_symbol(&lib, "timeGetTime64"),
+        callbacks: CallbackFns::load(&lib),
+        is_stream_open: stream_guard(initialize_kdmapi_stream),
+        library_name,
+        _lib: Some(Arc::new(lib)),
+    })
 }
 
 /// Struct that provides access to KDMAPI's stream functions
 ///
 /// Automatically calls `TerminateKDMAPIStream` when dropped.
 pub struct KDMAPIStream {
-    binds: &'static KDMAPIBinds,
+    binds: KDMAPIBinds,
 }
 
 impl KDMAPIStream {
@@ -92,15 +220,128 @@ impl Drop for KDMAPIStream {
         unsafe {
             (self.binds.terminate_kdmapi_stream)();
         }
-        self.binds
-            .is_stream_open
-            .store(false, std::sync::atomic::Ordering::Relaxed);
+        self.binds.is_stream_open.store(false, Ordering::Release);
     }
 }
 
 lazy_static! {
-    static ref KDMAPI_LIB: Library = load_kdmapi_lib();
-
     /// The dynamic library for KDMAPI. Is loaded when this field is accessed.
-    pub static ref KDMAPI: KDMAPIBinds = load_kdmapi_binds(&KDMAPI_LIB);
+    ///
+    /// This is a convenience global that stays loaded for the lifetime of the
+    /// process. Use [`KDMAPIBinds::try_load`] or [`KDMAPILoader`] for bindings
+    /// that can be dropped.
+    ///
+    /// Panics if OmniMIDI can't be loaded, use [`KDMAPIBinds::try_load`] to
+    /// handle that case instead.
+    pub static ref KDMAPI: KDMAPIBinds =
+        KDMAPIBinds::try_load().unwrap_or_else(|e| panic!("{}", e));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Barrier;
+    use std::thread;
+    use std::time::Duration;
+
+    static INIT_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe extern "C" fn is_kdmapi_available() -> bool {
+        true
+    }
+
+    unsafe extern "C" fn initialize_kdmapi_stream() -> i32 {
+        INIT_CALLS.fetch_add(1, Ordering::SeqCst);
+        // Widen the window in which a racing caller could slip through
+        thread::sleep(Duration::from_millis(20));
+        1
+    }
+
+    unsafe extern "C" fn terminate_kdmapi_stream() -> i32 {
+        1
+    }
+
+    unsafe extern "C" fn reset_kdmapi_stream() {}
+
+    unsafe extern "C" fn send_direct_data(_: u32) -> u32 {
+        0
+    }
+
+    fn test_binds() -> KDMAPIBinds {
+        KDMAPIBinds {
+            is_kdmapi_available,
+            initialize_kdmapi_stream,
+            terminate_kdmapi_stream,
+            reset_kdmapi_stream,
+            send_direct_data,
+            send_direct_data_no_buf: send_direct_data,
+            long_data: None,
+            return_kdmapi_ver: None,
+            driver_settings: None,
+            get_driver_debug_info: None,
+            load_custom_soundfonts_list: None,
+            time_get_time_64: None,
+            callbacks: None,
+            is_stream_open: Arc::new(AtomicBool::new(false)),
+            library_name: "test".into(),
+            _lib: None,
+        }
+    }
+
+    #[test]
+    fn optional_exports_can_be_missing() {
+        unsafe extern "C" fn initialize_quickly() -> i32 {
+            1
+        }
+        // Doesn't count towards `INIT_CALLS`
+        let binds = KDMAPIBinds {
+            initialize_kdmapi_stream: initialize_quickly,
+            ..test_binds()
+        };
+
+        assert!(binds.capabilities().is_empty());
+        assert_eq!(binds.version(), None);
+        assert_eq!(binds.time_get_time_64(), None);
+        let stream = binds.try_open_stream().unwrap();
+        assert_eq!(
+            stream.send_sysex(&[0xF0, 0x7E, 0x7F, 0x09, 0x01, 0xF7]),
+            Err(SysExError::Unsupported)
+        );
+        assert_eq!(
+            stream.settings().get(DriverSetting::MaxVoices),
+            Err(DriverSettingError::Unsupported)
+        );
+        assert_eq!(stream.debug_info(), None);
+    }
+
+    #[test]
+    fn concurrent_open_stream_initializes_once() {
+        let binds = test_binds();
+        let barrier = Arc::new(Barrier::new(8));
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let binds = binds.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    binds.open_stream()
+                })
+            })
+            .collect();
+        let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+        assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 1);
+        assert!(results
+            .iter()
+            .filter_map(|r| r.as_ref().err())
+            .all(|e| *e == StreamError::AlreadyOpen));
+        assert_eq!(INIT_CALLS.load(Ordering::SeqCst), 1);
+
+        drop(results);
+        let stream = binds.open_stream();
+        assert!(stream.is_ok());
+        assert_eq!(INIT_CALLS.load(Ordering::SeqCst), 2);
+    }
 }
@@ -0,0 +1,113 @@
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+use libloading::Library;
+
+use crate::{load_kdmapi_binds, KDMAPIBinds, KdmapiError};
+
+/// Environment variable checked by [`KDMAPILoader`] by default. If set, its
+/// value is the only candidate tried.
+pub const DEFAULT_ENV_VAR: &str = "KDMAPI_LIBRARY";
+
+/// Library names tried by [`KDMAPILoader`] by default, in order.
+pub const DEFAULT_LIBRARY_NAMES: &[&str] = &["OmniMIDI", "libOmniMIDI.so", "OmniMIDI.dll"];
+
+/// Builder for loading OmniMIDI from somewhere other than the default
+/// library search path.
+///
+/// If the environment variable (`KDMAPI_LIBRARY` by default) is set to a
+/// non-empty value, that library is loaded and nothing else is tried.
+/// Otherwise candidates are tried in this order, and the first one that loads
+/// is used:
+/// 1. Explicit paths, in the order they were added
+/// 2. Library names, resolved through the platform's search path
+#[derive(Debug, Clone)]
+pub struct KDMAPILoader {
+    env_var: Option<OsString>,
+    paths: Vec<PathBuf>,
+    names: Vec<OsString>,
+}
+
+impl KDMAPILoader {
+    /// Creates a loader using [`DEFAULT_ENV_VAR`] and [`DEFAULT_LIBRARY_NAMES`]
+    pub fn new() -> Self {
+        KDMAPILoader {
+            env_var: Some(DEFAULT_ENV_VAR.into()),
+            paths: Vec::new(),
+            names: DEFAULT_LIBRARY_NAMES.iter().map(OsString::from).collect(),
+        }
+    }
+
+    /// Adds an explicit file path to try, e.g. a copy bundled next to the
+    /// executable
+    pub fn path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.paths.push(path.into());
+        self
+    }
+
+    /// Sets the environment variable that overrides every other candidate.
+    /// If it is set but can't be loaded, loading fails rather than falling
+    /// back to the other candidates.
+    pub fn env_var(mut self, name: impl Into<OsString>) -> Self {
+        self.env_var = Some(name.into());
+        self
+    }
+
+    /// Disables the environment variable override
+    pub fn no_env_var(mut self) -> Self {
+        self.env_var = None;
+        self
+    }
+
+    /// Replaces the list of library names to resolve through the search path
+    pub fn names<I>(mut self, names: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<OsString>,
+    {
+        self.names = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Returns every candidate in the order they will be tried, which is only
+    /// the environment variable's value if it is set
+    pub fn candidates(&self) -> Vec<OsString> {
+        let env = self
+            .env_var
+            .as_ref()
+            .and_then(std::env::var_os)
+            .filter(|value| !value.is_empty());
+        if let Some(env) = env {
+            return vec![env];
+        }
+
+        self.paths
+            .iter()
+            .map(|p| p.clone().into_os_string())
+            .chain(self.names.iter().cloned())
+            .collect()
+    }
+
+    /// Loads the first candidate that exists and resolves the KDMAPI exports
+    /// from it. The chosen candidate is available through
+    /// [`KDMAPIBinds::library_name`].
+    ///
+    /// A library that loads but lacks a required export is reported as an
+    /// error rather than skipped.
+    pub fn load(&self) -> Result<KDMAPIBinds, KdmapiError> {
+        let mut attempts = Vec::new();
+        for candidate in self.candidates() {
+            match unsafe { Library::new(&candidate) } {
+                Ok(lib) => return load_kdmapi_binds(lib, candidate),
+                Err(e) => attempts.push((candidate, e)),
+            }
+        }
+        Err(KdmapiError::LibraryNotFound(attempts))
+    }
+}
+
+impl Default for KDMAPILoader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
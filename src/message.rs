@@ -0,0 +1,368 @@
+use std::fmt;
+
+use crate::{DecodeError, KDMAPIStream};
+
+macro_rules! checked_uint {
+    ($(#[$meta:meta])* $name:ident, $inner:ty, $bits:expr) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+        pub struct $name($inner);
+
+        impl $name {
+            /// The largest value this type can hold
+            pub const MAX: $name = $name((1 << $bits) - 1);
+
+            /// Returns `None` if `value` doesn't fit
+            pub const fn new(value: $inner) -> Option<Self> {
+                if value <= Self::MAX.0 {
+                    Some($name(value))
+                } else {
+                    None
+                }
+            }
+
+            /// Discards the bits of `value` that don't fit
+            pub const fn new_masked(value: $inner) -> Self {
+                $name(value & Self::MAX.0)
+            }
+
+            /// Returns the value as its underlying integer type
+            pub const fn get(self) -> $inner {
+                self.0
+            }
+        }
+
+        impl From<$name> for $inner {
+            fn from(value: $name) -> $inner {
+                value.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                self.0.fmt(f)
+            }
+        }
+    };
+}
+
+checked_uint!(
+    /// A 4-bit value, used for MIDI channels
+    U4,
+    u8,
+    4
+);
+checked_uint!(
+    /// A 7-bit value, used for MIDI data bytes
+    U7,
+    u8,
+    7
+);
+checked_uint!(
+    /// A 14-bit value, used for pitch bend
+    U14,
+    u16,
+    14
+);
+
+impl U14 {
+    /// The pitch bend value for no bend
+    pub const CENTER: U14 = U14(0x2000);
+
+    /// The least significant 7 bits
+    pub const fn lsb(self) -> U7 {
+        U7::new_masked(self.0 as u8)
+    }
+
+    /// The most significant 7 bits
+    pub const fn msb(self) -> U7 {
+        U7::new_masked((self.0 >> 7) as u8)
+    }
+
+    /// Builds a value from its two 7-bit halves
+    pub const fn from_lsb_msb(lsb: U7, msb: U7) -> Self {
+        U14((msb.0 as u16) << 7 | lsb.0 as u16)
+    }
+}
+
+/// A MIDI short message that can be sent through `SendDirectData`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MidiMessage {
+    NoteOff {
+        channel: U4,
+        key: U7,
+        velocity: U7,
+    },
+    NoteOn {
+        channel: U4,
+        key: U7,
+        velocity: U7,
+    },
+    PolyPressure {
+        channel: U4,
+        key: U7,
+        pressure: U7,
+    },
+    ControlChange {
+        channel: U4,
+        controller: U7,
+        value: U7,
+    },
+    ProgramChange {
+        channel: U4,
+        program: U7,
+    },
+    ChannelPressure {
+        channel: U4,
+        pressure: U7,
+    },
+    PitchBend {
+        channel: U4,
+        value: U14,
+    },
+    TimingClock,
+    Start,
+    Continue,
+    Stop,
+    ActiveSensing,
+    SystemReset,
+}
+
+impl MidiMessage {
+    /// The channel of a channel message, `None` for system realtime messages
+    pub fn channel(&self) -> Option<U4> {
+        match *self {
+            MidiMessage::NoteOff { channel, .. }
+            | MidiMessage::NoteOn { channel, .. }
+            | MidiMessage::PolyPressure { channel, .. }
+            | MidiMessage::ControlChange { channel, .. }
+            | MidiMessage::ProgramChange { channel, .. }
+            | MidiMessage::ChannelPressure { channel, .. }
+            | MidiMessage::PitchBend { channel, .. } => Some(channel),
+            _ => None,
+        }
+    }
+
+    /// The status byte of the message
+    pub fn status(&self) -> u8 {
+        let (kind, channel) = match *self {
+            MidiMessage::NoteOff { channel, .. } => (0x80, channel),
+            MidiMessage::NoteOn { channel, .. } => (0x90, channel),
+            MidiMessage::PolyPressure { channel, .. } => (0xA0, channel),
+            MidiMessage::ControlChange { channel, .. } => (0xB0, channel),
+            MidiMessage::ProgramChange { channel, .. } => (0xC0, channel),
+            MidiMessage::ChannelPressure { channel, .. } => (0xD0, channel),
+            MidiMessage::PitchBend { channel, .. } => (0xE0, channel),
+            MidiMessage::TimingClock => return 0xF8,
+            MidiMessage::Start => return 0xFA,
+            MidiMessage::Continue => return 0xFB,
+            MidiMessage::Stop => return 0xFC,
+            MidiMessage::ActiveSensing => return 0xFE,
+            MidiMessage::SystemReset => return 0xFF,
+        };
+        kind | channel.get()
+    }
+
+    /// Encodes the message as the DWORD taken by `SendDirectData`: the status
+    /// byte in the lowest byte, followed by the first and second data bytes.
+    pub fn to_kdmapi_u32(&self) -> u32 {
+        let (data1, data2) = match *self {
+            MidiMessage::NoteOff { key, velocity, .. }
+            | MidiMessage::NoteOn { key, velocity, .. } => (key.get(), velocity.get()),
+            MidiMessage::PolyPressure { key, pressure, .. } => (key.get(), pressure.get()),
+            MidiMessage::ControlChange {
+                controller, value, ..
+            } => (controller.get(), value.get()),
+            MidiMessage::ProgramChange { program, .. } => (program.get(), 0),
+            MidiMessage::ChannelPressure { pressure, .. } => (pressure.get(), 0),
+            MidiMessage::PitchBend { value, .. } => (value.lsb().get(), value.msb().get()),
+            _ => (0, 0),
+        };
+        self.status() as u32 | (data1 as u32) << 8 | (data2 as u32) << 16
+    }
+}
+
+impl MidiMessage {
+    /// Decodes a DWORD in the layout taken by `SendDirectData`. The highest
+    /// byte is ignored.
+    ///
+    /// A note on with a velocity of 0 is decoded as a note off with a
+    /// velocity of 0, as that's how every MIDI device interprets it.
+    pub fn from_kdmapi_u32(data: u32) -> Result<MidiMessage, DecodeError> {
+        let [status, data1, data2, _] = data.to_le_bytes();
+        MidiMessage::from_bytes(status, data1, data2)
+    }
+
+    /// Decodes a message from its status byte and up to two data bytes.
+    /// Data bytes that the message doesn't use are ignored.
+    pub(crate) fn from_bytes(status: u8, data1: u8, data2: u8) -> Result<MidiMessage, DecodeError> {
+        let data = |byte: u8| U7::new(byte).ok_or(DecodeError::InvalidDataByte(byte));
+        let channel = U4::new_masked(status);
+
+        let message = match status & 0xF0 {
+            0x80 => MidiMessage::NoteOff {
+                channel,
+                key: data(data1)?,
+                velocity: data(data2)?,
+            },
+            0x90 => {
+                let key = data(data1)?;
+                let velocity = data(data2)?;
+                if velocity.get() == 0 {
+                    MidiMessage::NoteOff {
+                        channel,
+                        key,
+                        velocity,
+                    }
+                } else {
+                    MidiMessage::NoteOn {
+                        channel,
+                        key,
+                        velocity,
+                    }
+                }
+            }
+            0xA0 => MidiMessage::PolyPressure {
+                channel,
+                key: data(data1)?,
+                pressure: data(data2)?,
+            },
+            0xB0 => MidiMessage::ControlChange {
+                channel,
+                controller: data(data1)?,
+                value: data(data2)?,
+            },
+            0xC0 => MidiMessage::ProgramChange {
+                channel,
+                program: data(data1)?,
+            },
+            0xD0 => MidiMessage::ChannelPressure {
+                channel,
+                pressure: data(data1)?,
+            },
+            0xE0 => MidiMessage::PitchBend {
+                channel,
+                value: U14::from_lsb_msb(data(data1)?, data(data2)?),
+            },
+            0xF0 => match status {
+                0xF8 => MidiMessage::TimingClock,
+                0xFA => MidiMessage::Start,
+                0xFB => MidiMessage::Continue,
+                0xFC => MidiMessage::Stop,
+                0xFE => MidiMessage::ActiveSensing,
+                0xFF => MidiMessage::SystemReset,
+                0xF0..=0xF7 => return Err(DecodeError::UnsupportedStatus(status)),
+                _ => return Err(DecodeError::InvalidStatus(status)),
+            },
+            _ => return Err(DecodeError::InvalidStatus(status)),
+        };
+        Ok(message)
+    }
+}
+
+impl fmt::Display for MidiMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            MidiMessage::NoteOff {
+                channel,
+                key,
+                velocity,
+            } => write!(f, "Note Off ch {} key {} vel {}", channel, key, velocity),
+            MidiMessage::NoteOn {
+                channel,
+                key,
+                velocity,
+            } => write!(f, "Note On ch {} key {} vel {}", channel, key, velocity),
+            MidiMessage::PolyPressure {
+                channel,
+                key,
+                pressure,
+            } => write!(f, "Poly Pressure ch {} key {} {}", channel, key, pressure),
+            MidiMessage::ControlChange {
+                channel,
+                controller,
+                value,
+            } => write!(
+                f,
+                "Control Change ch {} cc {} {}",
+                channel, controller, value
+            ),
+            MidiMessage::ProgramChange { channel, program } => {
+                write!(f, "Program Change ch {} program {}", channel, program)
+            }
+            MidiMessage::ChannelPressure { channel, pressure } => {
+                write!(f, "Channel Pressure ch {} {}", channel, pressure)
+            }
+            MidiMessage::PitchBend { channel, value } => {
+                write!(f, "Pitch Bend ch {} {}", channel, value)
+            }
+            MidiMessage::TimingClock => write!(f, "Timing Clock"),
+            MidiMessage::Start => write!(f, "Start"),
+            MidiMessage::Continue => write!(f, "Continue"),
+            MidiMessage::Stop => write!(f, "Stop"),
+            MidiMessage::ActiveSensing => write!(f, "Active Sensing"),
+            MidiMessage::SystemReset => write!(f, "System Reset"),
+        }
+    }
+}
+
+impl From<MidiMessage> for u32 {
+    fn from(message: MidiMessage) -> u32 {
+        message.to_kdmapi_u32()
+    }
+}
+
+impl KDMAPIStream {
+    /// Encodes `message` and calls `SendDirectData`
+    pub fn send(&self, message: MidiMessage) -> u32 {
+        self.send_direct_data(message.to_kdmapi_u32())
+    }
+
+    /// Encodes `message` and calls `SendDirectDataNoBuf`
+    pub fn send_no_buf(&self, message: MidiMessage) -> u32 {
+        self.send_direct_data_no_buf(message.to_kdmapi_u32())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_through_kdmapi_u32() {
+        for &data in &[
+            0x7F4090, 0x004080, 0x5A07B3, 0x0015C9, 0x40_00E2, 0xF8, 0xFF,
+        ] {
+            let message = MidiMessage::from_kdmapi_u32(data).unwrap();
+            assert_eq!(message.to_kdmapi_u32(), data);
+        }
+    }
+
+    #[test]
+    fn decodes_zero_velocity_note_on_as_note_off() {
+        assert_eq!(
+            MidiMessage::from_kdmapi_u32(0x003C91),
+            Ok(MidiMessage::NoteOff {
+                channel: U4::new(1).unwrap(),
+                key: U7::new(0x3C).unwrap(),
+                velocity: U7::new(0).unwrap(),
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_bytes() {
+        assert_eq!(
+            MidiMessage::from_kdmapi_u32(0x7F4040),
+            Err(DecodeError::InvalidStatus(0x40))
+        );
+        assert_eq!(
+            MidiMessage::from_kdmapi_u32(0x7FC090),
+            Err(DecodeError::InvalidDataByte(0xC0))
+        );
+        assert_eq!(
+            MidiMessage::from_kdmapi_u32(0xF2),
+            Err(DecodeError::UnsupportedStatus(0xF2))
+        );
+    }
+}
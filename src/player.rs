@@ -0,0 +1,1107 @@
+//! Real-time playback of MIDI files
+
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::smf::{EventKind, MetaEvent, Smf, Timing};
+use crate::tempo::{TempoCursor, TempoMap};
+use crate::{Clock, InstantClock, MidiSink, SmfError};
+
+mod chase;
+mod looping;
+mod streaming;
+
+use chase::ChaseState;
+use looping::{HeldNotes, Region};
+use streaming::StreamingSource;
+
+pub use looping::Loop;
+
+/// Waits shorter than this are spun instead of slept, as sleeping isn't
+/// precise enough
+const SPIN_THRESHOLD: Duration = Duration::from_millis(1);
+
+/// The longest the playback thread sleeps at once, so the reported position
+/// keeps moving between sparse events
+const MAX_SLEEP: Duration = Duration::from_millis(10);
+
+/// The slowest playback rate supported by [`Player::set_speed`]
+pub const MIN_SPEED: f64 = 0.01;
+
+/// The fastest playback rate supported by [`Player::set_speed`]
+pub const MAX_SPEED: f64 = 100.0;
+
+/// An event with its absolute time in ticks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TimedEvent {
+    pub(crate) tick: u64,
+    pub(crate) kind: EventKind,
+}
+
+/// Events in time order, from every track of a file
+///
+/// After returning an error, sources act as if they reached their end.
+pub(crate) trait EventSource: Send {
+    /// The next event, without consuming it
+    fn peek(&mut self) -> Result<Option<&TimedEvent>, SmfError>;
+
+    /// Consumes the event returned by the last `peek`
+    fn advance(&mut self);
+
+    /// Goes back to the first event
+    fn rewind(&mut self) -> Result<(), SmfError>;
+}
+
+/// A file whose tracks have been merged in memory
+struct MemorySource {
+    events: Vec<TimedEvent>,
+    index: usize,
+}
+
+impl MemorySource {
+    fn new(smf: &Smf) -> Self {
+        let mut events = Vec::with_capacity(smf.tracks.iter().map(Vec::len).sum());
+        smf.for_each_timed(|tick, kind| {
+            events.push(TimedEvent {
+                tick,
+                kind: kind.clone(),
+            })
+        });
+        // Stable, so simultaneous events keep their track and file order
+        events.sort_by_key(|e| e.tick);
+
+        MemorySource { events, index: 0 }
+    }
+}
+
+impl EventSource for MemorySource {
+    fn peek(&mut self) -> Result<Option<&TimedEvent>, SmfError> {
+        Ok(self.events.get(self.index))
+    }
+
+    fn advance(&mut self) {
+        self.index += 1;
+    }
+
+    fn rewind(&mut self) -> Result<(), SmfError> {
+        self.index = 0;
+        Ok(())
+    }
+}
+
+/// The playback state of a [`Player`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PlayerState {
+    Playing,
+    Paused,
+    /// Stopped and rewound to the start
+    Stopped,
+    /// Reached the end of the file
+    Finished,
+}
+
+/// A point in a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Position {
+    Ticks(u64),
+    /// Time from the start of the file
+    Time(Duration),
+}
+
+struct Control {
+    state: PlayerState,
+    seek: Option<Position>,
+    speed: f64,
+    looping: Option<Loop>,
+    clock: Option<Arc<dyn Clock>>,
+    quit: bool,
+    error: Option<SmfError>,
+}
+
+struct Shared {
+    control: Mutex<Control>,
+    changed: Condvar,
+    // Set under the lock whenever `control` changes, so the playback thread
+    // can check for commands without locking for every event
+    dirty: AtomicBool,
+    unbuffered: AtomicBool,
+    position_ticks: AtomicU64,
+    position_micros: AtomicU64,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Control> {
+        self.control.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Control)) {
+        let mut control = self.lock();
+        f(&mut control);
+        self.dirty.store(true, Ordering::Release);
+        self.changed.notify_all();
+    }
+}
+
+/// Plays a MIDI file into a [`MidiSink`] such as a
+/// [`KDMAPIStream`](crate::KDMAPIStream) in real time
+///
+/// Events from every track are merged in time order and dispatched from a
+/// dedicated thread. Event times are computed from the tempo map relative to
+/// a fixed starting point, so timing doesn't drift over long files. Time is
+/// measured with an [`InstantClock`] unless another [`Clock`] is set with
+/// [`Player::set_clock`].
+///
+/// The player starts paused. All notes are turned off when pausing, stopping,
+/// seeking, and when the player is dropped. Seeking chases the program,
+/// controller, pitch bend and RPN state of each channel, so playback resumes
+/// with the same sound it would have had when playing from the start.
+///
+/// Files can either be loaded fully with [`Player::new`], or streamed from
+/// disk with [`Player::open_streaming`].
+pub struct Player {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+    /// Only known for files that are loaded fully
+    tempo_map: Option<TempoMap>,
+    length_ticks: Option<u64>,
+}
+
+impl Player {
+    /// Creates a paused player for `smf` that sends its events to `sink`
+    pub fn new<S>(smf: &Smf, sink: S) -> Player
+    where
+        S: MidiSink + Send + 'static,
+    {
+        let source = MemorySource::new(smf);
+        let length_ticks = source.events.last().map_or(0, |e| e.tick);
+        let mut player = Player::spawn(Box::new(source), smf.header.timing, sink, false);
+        player.tempo_map = Some(TempoMap::from_smf(smf));
+        player.length_ticks = Some(length_ticks);
+        player
+    }
+
+    /// Creates a paused player that streams the file at `path` from disk
+    /// instead of loading it, for files too large to keep in memory such as
+    /// black MIDI.
+    ///
+    /// Each track is read lazily through its own small buffer and the tracks
+    /// are merged on the fly. Buffers shrink as the number of tracks grows,
+    /// so they take around 16 MiB at most. Short messages are sent
+    /// unbuffered, see
+    /// [`Player::set_unbuffered`].
+    ///
+    /// Errors reading the file after it was opened stop playback and can be
+    /// retrieved with [`Player::take_error`].
+    pub fn open_streaming<S>(path: impl AsRef<Path>, sink: S) -> Result<Player, SmfError>
+    where
+        S: MidiSink + Send + 'static,
+    {
+        let source = StreamingSource::open(path.as_ref())?;
+        let timing = source.header().timing;
+        Ok(Player::spawn(Box::new(source), timing, sink, true))
+    }
+
+    fn spawn<S>(source: Box<dyn EventSource>, timing: Timing, sink: S, unbuffered: bool) -> Player
+    where
+        S: MidiSink + Send + 'static,
+    {
+        let shared = Arc::new(Shared {
+            control: Mutex::new(Control {
+                state: PlayerState::Paused,
+                seek: None,
+                speed: 1.0,
+                looping: None,
+                clock: None,
+                quit: false,
+                error: None,
+            }),
+            changed: Condvar::new(),
+            dirty: AtomicBool::new(false),
+            unbuffered: AtomicBool::new(unbuffered),
+            position_ticks: AtomicU64::new(0),
+            position_micros: AtomicU64::new(0),
+        });
+
+        let engine = Engine {
+            sink,
+            source,
+            timing,
+            shared: shared.clone(),
+            tempo: TempoCursor::new(timing),
+            state: PlayerState::Paused,
+            speed: 1.0,
+            clock: Arc::new(InstantClock::new()),
+            anchor: Duration::ZERO,
+            anchor_micros: 0,
+            looping: None,
+            loops_done: 0,
+            marker_start: None,
+            held_notes: HeldNotes::new(),
+        };
+        let thread = std::thread::Builder::new()
+            .name("kdmapi-player".into())
+            .spawn(move || engine.run())
+            .expect("failed to spawn player thread");
+
+        Player {
+            shared,
+            thread: Some(thread),
+            tempo_map: None,
+            length_ticks: None,
+        }
+    }
+
+    /// Starts or resumes playback. Restarts from the beginning if the end of
+    /// the file was reached.
+    pub fn play(&self) {
+        self.shared.update(|c| {
+            if c.state == PlayerState::Finished {
+                c.seek = Some(Position::Ticks(0));
+            }
+            c.state = PlayerState::Playing;
+        });
+    }
+
+    /// Pauses playback at the current position
+    pub fn pause(&self) {
+        self.shared.update(|c| {
+            if c.state == PlayerState::Playing {
+                c.state = PlayerState::Paused;
+            }
+        });
+    }
+
+    /// Stops playback and rewinds to the start
+    pub fn stop(&self) {
+        self.shared.update(|c| {
+            c.state = PlayerState::Stopped;
+            c.seek = Some(Position::Ticks(0));
+        });
+    }
+
+    /// Moves to `tick`. Keeps playing if the player was playing, otherwise
+    /// leaves it paused at the new position.
+    pub fn seek_ticks(&self, tick: u64) {
+        self.seek_to(Position::Ticks(tick));
+    }
+
+    /// Moves to `time`. Keeps playing if the player was playing, otherwise
+    /// leaves it paused at the new position.
+    pub fn seek(&self, time: Duration) {
+        self.seek_to(Position::Time(time));
+    }
+
+    fn seek_to(&self, seek: Position) {
+        self.shared.update(|c| {
+            c.seek = Some(seek);
+            if c.state != PlayerState::Playing {
+                c.state = PlayerState::Paused;
+            }
+        });
+    }
+
+    pub fn state(&self) -> PlayerState {
+        self.shared.lock().state
+    }
+
+    /// Sets the playback rate, e.g. 0.5 for half speed or 2.0 for double
+    /// speed. Clamped to between [`MIN_SPEED`] and [`MAX_SPEED`].
+    ///
+    /// Takes effect immediately without interrupting playback. Only upcoming
+    /// events are rescheduled, and the reported position stays in file time.
+    ///
+    /// Panics if `speed` is NaN.
+    pub fn set_speed(&self, speed: f64) {
+        assert!(!speed.is_nan(), "playback speed is NaN");
+        let speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+        self.shared.update(|c| c.speed = speed);
+    }
+
+    /// The playback rate, 1.0 for normal speed
+    pub fn speed(&self) -> f64 {
+        self.shared.lock().speed
+    }
+
+    /// Sets the clock that events are scheduled against, e.g. a
+    /// [`DriverClock`](crate::DriverClock) so that they line up with the
+    /// driver's rendering timeline. Takes effect immediately without
+    /// interrupting playback.
+    pub fn set_clock(&self, clock: impl Clock + 'static) {
+        let clock: Arc<dyn Clock> = Arc::new(clock);
+        self.shared.update(|c| c.clock = Some(clock));
+    }
+
+    /// Repeats part or all of the file, or stops repeating with `None`.
+    ///
+    /// Held notes are released at the end of every pass, and the controller
+    /// state at the start of the loop is chased as when seeking. Passes follow
+    /// each other without gaps. Jumping back rereads the file up to the start
+    /// of the loop, which for streamed files means reading it from disk again.
+    pub fn set_loop(&self, looping: Option<Loop>) {
+        self.shared.update(|c| c.looping = looping);
+    }
+
+    /// Sets whether short messages are sent through
+    /// [`MidiSink::send_short_no_buf`] instead of [`MidiSink::send_short`].
+    /// Unbuffered sending has less overhead, which matters for very dense
+    /// files.
+    pub fn set_unbuffered(&self, unbuffered: bool) {
+        self.shared.unbuffered.store(unbuffered, Ordering::Relaxed);
+    }
+
+    /// Returns the error that stopped playback, if reading the file failed
+    pub fn take_error(&self) -> Option<SmfError> {
+        self.shared.lock().error.take()
+    }
+
+    /// The current position in ticks
+    pub fn position_ticks(&self) -> u64 {
+        self.shared.position_ticks.load(Ordering::Relaxed)
+    }
+
+    /// The current position as time from the start of the file
+    pub fn position(&self) -> Duration {
+        Duration::from_micros(self.shared.position_micros.load(Ordering::Relaxed))
+    }
+
+    /// The tempo map of the file, for converting positions to and from bars
+    /// and beats. `None` for streamed files, which are never read in full.
+    pub fn tempo_map(&self) -> Option<&TempoMap> {
+        self.tempo_map.as_ref()
+    }
+
+    /// The length of the file in ticks. `None` for streamed files.
+    pub fn length_ticks(&self) -> Option<u64> {
+        self.length_ticks
+    }
+
+    /// The length of the file. `None` for streamed files.
+    pub fn duration(&self) -> Option<Duration> {
+        let tick = self.length_ticks?;
+        Some(self.tempo_map.as_ref()?.tick_to_time(tick))
+    }
+
+    /// Blocks until the player is no longer playing, e.g. because it reached
+    /// the end of the file
+    pub fn wait(&self) {
+        let mut control = self.shared.lock();
+        while control.state == PlayerState::Playing {
+            control = self
+                .shared
+                .changed
+                .wait(control)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+impl Drop for Player {
+    fn drop(&mut self) {
+        self.shared.update(|c| c.quit = true);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// The state owned by the playback thread
+struct Engine<S> {
+    sink: S,
+    source: Box<dyn EventSource>,
+    timing: Timing,
+    shared: Arc<Shared>,
+    tempo: TempoCursor,
+    state: PlayerState,
+    speed: f64,
+    clock: Arc<dyn Clock>,
+    /// The clock time at which the file was at `anchor_micros`. Times after
+    /// it are scaled by `speed`.
+    anchor: Duration,
+    anchor_micros: u64,
+    looping: Option<Loop>,
+    /// Passes completed since the loop was set or playback restarted
+    loops_done: u32,
+    /// The tick of the last loop start marker played, for [`Loop::markers`]
+    marker_start: Option<u64>,
+    held_notes: HeldNotes,
+}
+
+impl<S: MidiSink> Engine<S> {
+    fn run(mut self) {
+        raise_thread_priority();
+
+        loop {
+            if self.shared.dirty.swap(false, Ordering::Acquire) && !self.apply_control() {
+                break;
+            }
+            if self.state != PlayerState::Playing {
+                self.wait_for_control();
+                continue;
+            }
+
+            let (event_tick, at_loop_end) = match self.source.peek() {
+                Ok(Some(event)) => (Some(event.tick), looping::is_loop_end(&event.kind)),
+                Ok(None) => (None, false),
+                Err(e) => {
+                    self.finish(Some(e));
+                    continue;
+                }
+            };
+            if let Some(end_micros) = self.loop_end(event_tick, at_loop_end) {
+                let now = self.clock.now();
+                let due = self.due_time(end_micros);
+                if due > now {
+                    self.publish_position(self.micros_at_time(now).min(end_micros));
+                    self.wait_until(now, due);
+                } else {
+                    self.jump_to_loop_start(end_micros, due);
+                }
+                continue;
+            }
+            let event_micros = match event_tick {
+                Some(tick) => self.tempo.micros_at(tick),
+                None => {
+                    self.finish(None);
+                    continue;
+                }
+            };
+            let now = self.clock.now();
+            let due = self.due_time(event_micros);
+            if due > now {
+                self.publish_position(self.micros_at_time(now).min(event_micros));
+                self.wait_until(now, due);
+                continue;
+            }
+
+            self.dispatch_next();
+        }
+
+        self.silence();
+    }
+
+    /// Applies pending commands, returns false if the thread should quit
+    fn apply_control(&mut self) -> bool {
+        let (state, seek, speed, looping, clock) = {
+            let mut control = self.shared.lock();
+            if control.quit {
+                return false;
+            }
+            (
+                control.state,
+                control.seek.take(),
+                control.speed,
+                control.looping,
+                control.clock.take(),
+            )
+        };
+        if looping != self.looping
+            || state == PlayerState::Stopped
+            || self.state == PlayerState::Finished
+        {
+            self.looping = looping;
+            self.loops_done = 0;
+        }
+
+        let was_playing = self.state == PlayerState::Playing;
+        if was_playing && (state != PlayerState::Playing || speed != self.speed || clock.is_some())
+        {
+            // Continue from where playback actually got to, which is never
+            // past the next undispatched event, so that events already sent
+            // stay where they were
+            let now = self.clock.now();
+            let mut micros = self.micros_at_time(now);
+            if let Ok(Some(event)) = self.source.peek() {
+                micros = micros.min(self.tempo.micros_at(event.tick));
+            }
+            self.anchor = now;
+            self.anchor_micros = micros;
+            self.publish_position(micros);
+        }
+        self.speed = speed;
+        if let Some(clock) = clock {
+            self.clock = clock;
+            self.anchor = self.clock.now();
+        }
+        if was_playing && state != PlayerState::Playing {
+            self.silence();
+        }
+        if let Some(seek) = seek {
+            self.seek(seek);
+        }
+        if state == PlayerState::Playing && (!was_playing || seek.is_some()) {
+            self.anchor = self.clock.now();
+        }
+        self.state = state;
+        true
+    }
+
+    fn silence(&mut self) {
+        self.sink.all_notes_off();
+        self.held_notes.clear();
+    }
+
+    fn seek(&mut self, seek: Position) {
+        self.silence();
+        self.rewind_to(seek);
+    }
+
+    /// Rewinds and skips ahead to `seek` without sending any notes. The
+    /// program, controller, pitch bend and RPN state of each channel at the
+    /// new position is chased and sent once the position is reached.
+    fn rewind_to(&mut self, seek: Position) {
+        if let Err(e) = self.source.rewind() {
+            self.shared.lock().error = Some(e);
+        }
+        self.tempo = TempoCursor::new(self.timing);
+        self.marker_start = None;
+        let mut chase = ChaseState::new();
+
+        loop {
+            let event = match self.source.peek() {
+                Ok(Some(event)) => event,
+                Ok(None) => break,
+                Err(e) => {
+                    self.shared.lock().error = Some(e);
+                    break;
+                }
+            };
+            let reached = match seek {
+                Position::Ticks(tick) => event.tick >= tick,
+                Position::Time(time) => self.tempo.micros_at(event.tick) >= time.as_micros() as u64,
+            };
+            if reached {
+                break;
+            }
+            if looping::is_loop_start(&event.kind) {
+                self.marker_start = Some(event.tick);
+            }
+            match event.kind {
+                EventKind::Meta(MetaEvent::Tempo(tempo)) => self.tempo.set_tempo(event.tick, tempo),
+                EventKind::Midi(ref message) => chase.record(message),
+                _ => {}
+            }
+            self.source.advance();
+        }
+        chase.replay(&self.sink);
+
+        let micros = match seek {
+            Position::Ticks(tick) => self.tempo.micros_at(tick),
+            Position::Time(time) => time.as_micros() as u64,
+        };
+        self.anchor = self.clock.now();
+        self.anchor_micros = micros;
+        self.publish_position(micros);
+    }
+
+    /// The time at which playback should jump back to the start of the loop,
+    /// if the next event at `next_tick` is at or past the end of the loop and
+    /// there are passes left
+    fn loop_end(&self, next_tick: Option<u64>, next_is_end_marker: bool) -> Option<u64> {
+        let looping = self.looping?;
+        if matches!(looping.count, Some(count) if self.loops_done + 1 >= count) {
+            return None;
+        }
+        // Only the last dispatched event can have set the position once the
+        // file has run out
+        let file_end = || self.shared.position_micros.load(Ordering::Relaxed);
+
+        match (looping.region, next_tick) {
+            (Region::Range { end: None, .. }, None) => Some(file_end()),
+            (Region::Range { end: None, .. }, Some(_)) => None,
+            (Region::Range { end: Some(end), .. }, next_tick) => {
+                let end_micros = match end {
+                    Position::Ticks(tick) => self.tempo.micros_at(tick),
+                    Position::Time(time) => time.as_micros() as u64,
+                };
+                let reached = match (end, next_tick) {
+                    (_, None) => true,
+                    (Position::Ticks(end), Some(tick)) => tick >= end,
+                    (Position::Time(_), Some(tick)) => self.tempo.micros_at(tick) >= end_micros,
+                };
+                if reached {
+                    Some(end_micros)
+                } else {
+                    None
+                }
+            }
+            (Region::Markers, _) if self.marker_start.is_none() => None,
+            (Region::Markers, None) => Some(file_end()),
+            (Region::Markers, Some(tick)) => next_is_end_marker.then(|| self.tempo.micros_at(tick)),
+        }
+    }
+
+    /// Releases held notes and goes back to the start of the loop. The next
+    /// pass starts at `due`, when the last one ended, so loops don't drift.
+    fn jump_to_loop_start(&mut self, end_micros: u64, due: Duration) {
+        let start = match self.looping.map(|l| l.region) {
+            Some(Region::Range { start, .. }) => start,
+            Some(Region::Markers) => Position::Ticks(self.marker_start.unwrap_or(0)),
+            None => return,
+        };
+        // A start before the last tempo change is before the end too
+        let inverted = match start {
+            Position::Ticks(tick) => matches!(
+                self.tempo.checked_micros_at(tick),
+                Some(micros) if micros >= end_micros
+            ),
+            Position::Time(time) => time.as_micros() as u64 >= end_micros,
+        };
+        if inverted {
+            // Jumping "back" would skip ahead, so play on as if not looping
+            self.stop_looping();
+            return;
+        }
+        self.held_notes.release(&self.sink);
+        self.rewind_to(start);
+        if self.anchor_micros >= end_micros {
+            // An empty loop would never advance
+            self.stop_looping();
+            return;
+        }
+        self.loops_done += 1;
+        self.anchor = due;
+    }
+
+    /// Drops the current loop, also from the commands so that it isn't
+    /// picked up again unless set again
+    fn stop_looping(&mut self) {
+        let mut control = self.shared.lock();
+        if control.looping == self.looping {
+            control.looping = None;
+        }
+        self.looping = None;
+    }
+
+    fn dispatch_next(&mut self) {
+        let unbuffered = self.shared.unbuffered.load(Ordering::Relaxed);
+        if let Ok(Some(event)) = self.source.peek() {
+            let micros = self.tempo.micros_at(event.tick);
+            if looping::is_loop_start(&event.kind) {
+                self.marker_start = Some(event.tick);
+            }
+            if let EventKind::Midi(ref message) = event.kind {
+                self.held_notes.track(message);
+            }
+            match event.kind {
+                EventKind::Meta(MetaEvent::Tempo(tempo)) => {
+                    self.tempo.set_tempo(event.tick, tempo);
+                }
+                EventKind::Midi(message) if unbuffered => {
+                    self.sink.send_short_no_buf(message.to_kdmapi_u32());
+                }
+                ref kind => {
+                    // The driver rejecting a SysEx message shouldn't stop playback
+                    let _ = kind.send_to(&self.sink);
+                }
+            }
+            let tick = event.tick;
+            self.source.advance();
+            self.shared.position_ticks.store(tick, Ordering::Relaxed);
+            self.shared.position_micros.store(micros, Ordering::Relaxed);
+        }
+    }
+
+    fn finish(&mut self, error: Option<SmfError>) {
+        let mut control = self.shared.lock();
+        if error.is_some() {
+            control.error = error;
+        }
+        if control.state == PlayerState::Playing {
+            control.state = PlayerState::Finished;
+            self.shared.changed.notify_all();
+        }
+        self.state = PlayerState::Finished;
+    }
+
+    fn publish_position(&self, micros: u64) {
+        self.shared
+            .position_ticks
+            .store(self.tempo.tick_at(micros), Ordering::Relaxed);
+        self.shared.position_micros.store(micros, Ordering::Relaxed);
+    }
+
+    /// The clock time at which the file reaches `micros`
+    fn due_time(&self, micros: u64) -> Duration {
+        let file_micros = micros.saturating_sub(self.anchor_micros);
+        if self.speed == 1.0 {
+            self.anchor + Duration::from_micros(file_micros)
+        } else {
+            self.anchor + Duration::from_secs_f64(file_micros as f64 / 1e6 / self.speed)
+        }
+    }
+
+    /// The file time at the clock time `time`
+    fn micros_at_time(&self, time: Duration) -> u64 {
+        let elapsed = time.saturating_sub(self.anchor);
+        if self.speed == 1.0 {
+            self.anchor_micros + elapsed.as_micros() as u64
+        } else {
+            self.anchor_micros + (elapsed.as_secs_f64() * self.speed * 1e6) as u64
+        }
+    }
+
+    /// Sleeps or spins until `due`, returning early if a command arrives
+    fn wait_until(&self, now: Duration, due: Duration) {
+        let remaining = due - now;
+        if remaining > SPIN_THRESHOLD {
+            let control = self.shared.lock();
+            if !self.shared.dirty.load(Ordering::Acquire) {
+                let timeout = (remaining - SPIN_THRESHOLD).min(MAX_SLEEP);
+                let _ = self.shared.changed.wait_timeout(control, timeout);
+            }
+        } else {
+            while self.clock.now() < due && !self.shared.dirty.load(Ordering::Relaxed) {
+                std::hint::spin_loop();
+            }
+        }
+    }
+
+    fn wait_for_control(&self) {
+        let mut control = self.shared.lock();
+        while !self.shared.dirty.load(Ordering::Acquire) {
+            control = self
+                .shared
+                .changed
+                .wait(control)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+/// Raises the priority of the current thread where that doesn't require
+/// special privileges
+#[cfg(windows)]
+fn raise_thread_priority() {
+    const THREAD_PRIORITY_TIME_CRITICAL: i32 = 15;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetCurrentThread() -> isize;
+        fn SetThreadPriority(thread: isize, priority: i32) -> i32;
+    }
+
+    unsafe {
+        SetThreadPriority(GetCurrentThread(), THREAD_PRIORITY_TIME_CRITICAL);
+    }
+}
+
+#[cfg(not(windows))]
+fn raise_thread_priority() {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smf::{Format, Header, TrackEvent};
+    use crate::{MidiMessage, RecordedCall, RecordingSink, U4, U7};
+    use std::time::Instant;
+
+    fn note(key: u8, on: bool) -> EventKind {
+        let (channel, key) = (U4::new(0).unwrap(), U7::new(key).unwrap());
+        EventKind::Midi(if on {
+            MidiMessage::NoteOn {
+                channel,
+                key,
+                velocity: U7::MAX,
+            }
+        } else {
+            MidiMessage::NoteOff {
+                channel,
+                key,
+                velocity: U7::new(0).unwrap(),
+            }
+        })
+    }
+
+    fn event(delta: u32, kind: EventKind) -> TrackEvent {
+        TrackEvent { delta, kind }
+    }
+
+    /// A clock that only moves when told to
+    #[derive(Clone, Default)]
+    struct ManualClock(Arc<AtomicU64>);
+
+    impl ManualClock {
+        fn set(&self, time: Duration) {
+            self.0.store(time.as_micros() as u64, Ordering::Relaxed);
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Duration {
+            Duration::from_micros(self.0.load(Ordering::Relaxed))
+        }
+    }
+
+    #[test]
+    fn plays_tracks_merged_in_time_order() {
+        // 100 ticks per quarter at 24ms per quarter, so each tick is 240us
+        let smf = Smf {
+            header: Header {
+                format: Format::Parallel,
+                track_count: 2,
+                timing: Timing::Metrical(100),
+            },
+            tracks: vec![
+                vec![
+                    event(0, EventKind::Meta(MetaEvent::Tempo(24_000))),
+                    event(0, note(60, true)),
+                    event(100, note(60, false)),
+                ],
+                vec![event(50, note(64, true)), event(100, note(64, false))],
+            ],
+        };
+        let sink = Arc::new(RecordingSink::new());
+        let player = Player::new(&smf, sink.clone());
+
+        let start = Instant::now();
+        player.play();
+        player.wait();
+
+        assert!(start.elapsed() >= Duration::from_micros(150 * 240));
+        assert_eq!(player.state(), PlayerState::Finished);
+        assert_eq!(player.duration(), Some(player.position()));
+        assert_eq!(player.position_ticks(), 150);
+        assert_eq!(player.position(), Duration::from_micros(150 * 240));
+        let notes: Vec<_> = sink
+            .calls()
+            .into_iter()
+            .filter(|call| matches!(call, RecordedCall::DirectData(_)))
+            .map(|call| EventKind::Midi(call.message().unwrap()))
+            .collect();
+        assert_eq!(
+            notes,
+            vec![
+                note(60, true),
+                note(64, true),
+                note(60, false),
+                note(64, false)
+            ]
+        );
+    }
+
+    #[test]
+    fn speed_scales_playback_time() {
+        // 400ms of file time at the default tempo
+        let smf = Smf {
+            header: Header {
+                format: Format::SingleTrack,
+                track_count: 1,
+                timing: Timing::Metrical(100),
+            },
+            tracks: vec![vec![event(0, note(60, true)), event(80, note(60, false))]],
+        };
+        let clock = ManualClock::default();
+        let sink = Arc::new(RecordingSink::new());
+        let player = Player::new(&smf, sink.clone());
+        player.set_clock(clock.clone());
+        player.set_speed(4.0);
+        player.play();
+        // The clock must not move before playback has started
+        while sink.is_empty() {
+            std::thread::yield_now();
+        }
+
+        // Give the player a chance to send the note off too early
+        clock.set(Duration::from_millis(99));
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(player.state(), PlayerState::Playing);
+        assert_eq!(sink.len(), 1);
+
+        clock.set(Duration::from_millis(101));
+        player.wait();
+        assert_eq!(sink.len(), 2);
+        assert_eq!(player.position(), Duration::from_millis(400));
+    }
+
+    #[test]
+    fn loops_from_marker_releasing_held_notes() {
+        // 5ms per tick at the default tempo
+        let loop_start = EventKind::Midi(MidiMessage::ControlChange {
+            channel: U4::new(0).unwrap(),
+            controller: U7::new(111).unwrap(),
+            value: U7::new(0).unwrap(),
+        });
+        let smf = Smf {
+            header: Header {
+                format: Format::SingleTrack,
+                track_count: 1,
+                timing: Timing::Metrical(100),
+            },
+            tracks: vec![vec![
+                event(0, note(60, true)),
+                event(10, loop_start),
+                event(0, note(62, true)),
+                event(20, EventKind::Meta(MetaEvent::EndOfTrack)),
+            ]],
+        };
+        let sink = Arc::new(RecordingSink::new());
+        let player = Player::new(&smf, sink.clone());
+        player.set_loop(Some(Loop::markers().times(3)));
+
+        let start = Instant::now();
+        player.play();
+        player.wait();
+
+        // Each pass after the first only plays the 100ms from the loop start
+        assert!(start.elapsed() >= Duration::from_millis(250));
+        assert_eq!(player.state(), PlayerState::Finished);
+        let notes: Vec<_> = sink
+            .calls()
+            .into_iter()
+            .filter_map(|call| call.message())
+            .filter(|message| {
+                matches!(
+                    message,
+                    MidiMessage::NoteOn { .. } | MidiMessage::NoteOff { .. }
+                )
+            })
+            .map(EventKind::Midi)
+            .collect();
+        assert_eq!(
+            notes,
+            vec![
+                note(60, true),
+                note(62, true),
+                note(60, false),
+                note(62, false),
+                note(62, true),
+                note(62, false),
+                note(62, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn inverted_loop_plays_through() {
+        // A note every 5ms
+        let smf = Smf {
+            header: Header {
+                format: Format::SingleTrack,
+                track_count: 1,
+                timing: Timing::Metrical(1000),
+            },
+            tracks: vec![(0..10)
+                .map(|i| event(if i == 0 { 0 } else { 10 }, note(60 + i, true)))
+                .collect()],
+        };
+        let sink = Arc::new(RecordingSink::new());
+        let player = Player::new(&smf, sink.clone());
+        player.set_loop(Some(Loop::between(
+            Position::Ticks(80),
+            Position::Ticks(30),
+        )));
+        player.play();
+        player.wait();
+
+        assert_eq!(player.state(), PlayerState::Finished);
+        let notes: Vec<_> = sink
+            .calls()
+            .into_iter()
+            .filter_map(|call| call.message())
+            .filter(|message| matches!(message, MidiMessage::NoteOn { .. }))
+            .map(EventKind::Midi)
+            .collect();
+        assert_eq!(
+            notes,
+            (0..10).map(|i| note(60 + i, true)).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn seeking_chases_state_without_replaying_notes() {
+        // 5ms per tick at the default tempo
+        let (channel, other) = (U4::new(0).unwrap(), U4::new(1).unwrap());
+        let cc = |channel, controller, value| MidiMessage::ControlChange {
+            channel,
+            controller: U7::new(controller).unwrap(),
+            value: U7::new(value).unwrap(),
+        };
+        let midi = |delta, message| event(delta, EventKind::Midi(message));
+        let smf = Smf {
+            header: Header {
+                format: Format::SingleTrack,
+                track_count: 1,
+                timing: Timing::Metrical(100),
+            },
+            tracks: vec![vec![
+                midi(0, cc(channel, 0, 1)),
+                midi(0, cc(channel, 32, 2)),
+                midi(
+                    0,
+                    MidiMessage::ProgramChange {
+                        channel,
+                        program: U7::new(5).unwrap(),
+                    },
+                ),
+                midi(0, cc(channel, 7, 100)),
+                // Pitch bend range of 12 semitones
+                midi(0, cc(channel, 101, 0)),
+                midi(0, cc(channel, 100, 0)),
+                midi(0, cc(channel, 6, 12)),
+                event(0, note(60, true)),
+                event(10, note(60, false)),
+                midi(40, cc(channel, 7, 80)),
+                event(0, note(61, true)),
+                event(50, note(62, true)),
+                event(10, note(62, false)),
+            ]],
+        };
+        let sink = Arc::new(RecordingSink::new());
+        let player = Player::new(&smf, sink.clone());
+
+        player.seek_ticks(100);
+        while player.position() != Duration::from_millis(500) {
+            std::thread::yield_now();
+        }
+        assert_eq!(player.state(), PlayerState::Paused);
+        assert_eq!(player.position_ticks(), 100);
+        player.play();
+        player.wait();
+
+        let messages: Vec<_> = sink
+            .calls()
+            .into_iter()
+            .filter_map(|call| call.message())
+            .filter(|message| message.channel() == Some(channel))
+            .map(EventKind::Midi)
+            .collect();
+        let expected: Vec<_> = vec![
+            // Silenced, then reset before the chased state
+            cc(channel, 120, 0),
+            cc(channel, 123, 0),
+            cc(channel, 121, 0),
+            cc(channel, 0, 1),
+            cc(channel, 32, 2),
+            MidiMessage::ProgramChange {
+                channel,
+                program: U7::new(5).unwrap(),
+            },
+            cc(channel, 7, 80),
+            cc(channel, 101, 0),
+            cc(channel, 100, 0),
+            cc(channel, 6, 12),
+            // The RPN is left selected, as it was in the file
+            cc(channel, 101, 0),
+            cc(channel, 100, 0),
+        ]
+        .into_iter()
+        .map(EventKind::Midi)
+        .chain(vec![note(62, true), note(62, false)])
+        .collect();
+        assert_eq!(messages, expected);
+        // Other channels are only silenced and reset
+        let other_messages: Vec<_> = sink
+            .calls()
+            .into_iter()
+            .filter_map(|call| call.message())
+            .filter(|message| message.channel() == Some(other))
+            .collect();
+        assert_eq!(
+            other_messages,
+            vec![cc(other, 120, 0), cc(other, 123, 0), cc(other, 121, 0)]
+        );
+    }
+}
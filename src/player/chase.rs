@@ -0,0 +1,348 @@
+use crate::{MidiMessage, MidiSink, U14, U4, U7};
+
+const BANK_SELECT_MSB: u8 = 0;
+const DATA_ENTRY_MSB: u8 = 6;
+const VOLUME: u8 = 7;
+const PAN: u8 = 10;
+const BANK_SELECT_LSB: u8 = 32;
+const DATA_ENTRY_LSB: u8 = 38;
+const DATA_INCREMENT: u8 = 96;
+const DATA_DECREMENT: u8 = 97;
+const NRPN_LSB: u8 = 98;
+const NRPN_MSB: u8 = 99;
+const RPN_LSB: u8 = 100;
+const RPN_MSB: u8 = 101;
+const RESET_ALL_CONTROLLERS: u8 = 121;
+
+/// Controllers that are chased as plain values. Data entry and parameter
+/// selection are chased per parameter, and channel mode messages not at all.
+fn is_chased_controller(controller: u8) -> bool {
+    controller < 120
+        && controller != DATA_ENTRY_MSB
+        && controller != DATA_ENTRY_LSB
+        && !(DATA_INCREMENT..=RPN_MSB).contains(&controller)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Parameter {
+    Rpn(U7, U7),
+    Nrpn(U7, U7),
+}
+
+impl Parameter {
+    fn select(self, channel: U4, sink: &impl MidiSink) {
+        let (controllers, msb, lsb) = match self {
+            Parameter::Rpn(msb, lsb) => ((RPN_MSB, RPN_LSB), msb, lsb),
+            Parameter::Nrpn(msb, lsb) => ((NRPN_MSB, NRPN_LSB), msb, lsb),
+        };
+        send_cc(sink, channel, controllers.0, msb);
+        send_cc(sink, channel, controllers.1, lsb);
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ParameterValue {
+    parameter: Parameter,
+    msb: Option<U7>,
+    lsb: Option<U7>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ParameterKind {
+    Rpn,
+    Nrpn,
+}
+
+#[derive(Debug, Clone)]
+struct ChannelState {
+    program: Option<U7>,
+    controllers: [Option<U7>; 128],
+    pitch_bend: Option<U14>,
+    pressure: Option<U7>,
+    rpn: (Option<U7>, Option<U7>),
+    nrpn: (Option<U7>, Option<U7>),
+    /// Whether an RPN or NRPN was selected last
+    selected: Option<ParameterKind>,
+    parameters: Vec<ParameterValue>,
+}
+
+impl ChannelState {
+    fn new() -> Self {
+        ChannelState {
+            program: None,
+            controllers: [None; 128],
+            pitch_bend: None,
+            pressure: None,
+            rpn: (None, None),
+            nrpn: (None, None),
+            selected: None,
+            parameters: Vec::new(),
+        }
+    }
+
+    fn selected_parameter(&self) -> Option<Parameter> {
+        match self.selected? {
+            ParameterKind::Rpn => match self.rpn {
+                // The null RPN deselects
+                (Some(msb), Some(lsb)) if (msb, lsb) != (U7::MAX, U7::MAX) => {
+                    Some(Parameter::Rpn(msb, lsb))
+                }
+                _ => None,
+            },
+            ParameterKind::Nrpn => match self.nrpn {
+                (Some(msb), Some(lsb)) if (msb, lsb) != (U7::MAX, U7::MAX) => {
+                    Some(Parameter::Nrpn(msb, lsb))
+                }
+                _ => None,
+            },
+        }
+    }
+
+    fn selected_value(&mut self) -> Option<&mut ParameterValue> {
+        let parameter = self.selected_parameter()?;
+        let index = match self
+            .parameters
+            .iter()
+            .position(|p| p.parameter == parameter)
+        {
+            Some(index) => index,
+            None => {
+                self.parameters.push(ParameterValue {
+                    parameter,
+                    msb: None,
+                    lsb: None,
+                });
+                self.parameters.len() - 1
+            }
+        };
+        Some(&mut self.parameters[index])
+    }
+
+    fn control_change(&mut self, controller: U7, value: U7) {
+        match controller.get() {
+            RPN_MSB => {
+                self.rpn.0 = Some(value);
+                self.selected = Some(ParameterKind::Rpn);
+            }
+            RPN_LSB => {
+                self.rpn.1 = Some(value);
+                self.selected = Some(ParameterKind::Rpn);
+            }
+            NRPN_MSB => {
+                self.nrpn.0 = Some(value);
+                self.selected = Some(ParameterKind::Nrpn);
+            }
+            NRPN_LSB => {
+                self.nrpn.1 = Some(value);
+                self.selected = Some(ParameterKind::Nrpn);
+            }
+            DATA_ENTRY_MSB => {
+                if let Some(parameter) = self.selected_value() {
+                    parameter.msb = Some(value);
+                }
+            }
+            DATA_ENTRY_LSB => {
+                if let Some(parameter) = self.selected_value() {
+                    parameter.lsb = Some(value);
+                }
+            }
+            controller @ (DATA_INCREMENT | DATA_DECREMENT) => {
+                if let Some(parameter) = self.selected_value() {
+                    let msb = parameter.msb.unwrap_or_default();
+                    let lsb = parameter.lsb.unwrap_or_default();
+                    let current = U14::from_lsb_msb(lsb, msb).get();
+                    let next = if controller == DATA_INCREMENT {
+                        current.saturating_add(1).min(U14::MAX.get())
+                    } else {
+                        current.saturating_sub(1)
+                    };
+                    let next = U14::new_masked(next);
+                    parameter.msb = Some(next.msb());
+                    parameter.lsb = Some(next.lsb());
+                }
+            }
+            RESET_ALL_CONTROLLERS => {
+                // Following RP-015, which leaves bank, volume, pan and effect
+                // depths alone
+                for (controller, value) in self.controllers.iter_mut().enumerate() {
+                    let kept = matches!(
+                        controller as u8,
+                        BANK_SELECT_MSB | BANK_SELECT_LSB | VOLUME | PAN | 91..=95
+                    );
+                    if !kept {
+                        *value = None;
+                    }
+                }
+                self.pitch_bend = None;
+                self.pressure = None;
+                self.rpn = (None, None);
+                self.nrpn = (None, None);
+                self.selected = None;
+            }
+            controller if is_chased_controller(controller) => {
+                self.controllers[controller as usize] = Some(value);
+            }
+            _ => {}
+        }
+    }
+
+    fn replay(&self, channel: U4, sink: &impl MidiSink) {
+        for &controller in &[BANK_SELECT_MSB, BANK_SELECT_LSB] {
+            if let Some(value) = self.controllers[controller as usize] {
+                send_cc(sink, channel, controller, value);
+            }
+        }
+        if let Some(program) = self.program {
+            sink.send(MidiMessage::ProgramChange { channel, program });
+        }
+        for (controller, value) in self.controllers.iter().enumerate() {
+            let controller = controller as u8;
+            if controller == BANK_SELECT_MSB || controller == BANK_SELECT_LSB {
+                continue;
+            }
+            if let Some(value) = *value {
+                send_cc(sink, channel, controller, value);
+            }
+        }
+
+        for parameter in &self.parameters {
+            parameter.parameter.select(channel, sink);
+            if let Some(msb) = parameter.msb {
+                send_cc(sink, channel, DATA_ENTRY_MSB, msb);
+            }
+            if let Some(lsb) = parameter.lsb {
+                send_cc(sink, channel, DATA_ENTRY_LSB, lsb);
+            }
+        }
+        match self.selected_parameter() {
+            Some(parameter) => parameter.select(channel, sink),
+            None if !self.parameters.is_empty() => {
+                Parameter::Rpn(U7::MAX, U7::MAX).select(channel, sink)
+            }
+            None => {}
+        }
+
+        if let Some(value) = self.pitch_bend {
+            sink.send(MidiMessage::PitchBend { channel, value });
+        }
+        if let Some(pressure) = self.pressure {
+            sink.send(MidiMessage::ChannelPressure { channel, pressure });
+        }
+    }
+}
+
+fn send_cc(sink: &impl MidiSink, channel: U4, controller: u8, value: U7) {
+    sink.send(MidiMessage::ControlChange {
+        channel,
+        controller: U7::new_masked(controller),
+        value,
+    });
+}
+
+/// The last known program, bank, controller, pitch bend and RPN/NRPN state of
+/// every channel, collected while skipping events during a seek so it can be
+/// restored at the new position
+pub(crate) struct ChaseState {
+    channels: Vec<ChannelState>,
+}
+
+impl ChaseState {
+    pub(crate) fn new() -> Self {
+        ChaseState {
+            channels: vec![ChannelState::new(); 16],
+        }
+    }
+
+    /// Records a skipped message. Notes are ignored.
+    pub(crate) fn record(&mut self, message: &MidiMessage) {
+        let channel = match message.channel() {
+            Some(channel) => &mut self.channels[channel.get() as usize],
+            None => return,
+        };
+        match *message {
+            MidiMessage::ProgramChange { program, .. } => channel.program = Some(program),
+            MidiMessage::ControlChange {
+                controller, value, ..
+            } => channel.control_change(controller, value),
+            MidiMessage::PitchBend { value, .. } => channel.pitch_bend = Some(value),
+            MidiMessage::ChannelPressure { pressure, .. } => channel.pressure = Some(pressure),
+            _ => {}
+        }
+    }
+
+    /// Resets every channel's controllers, then sends the recorded state
+    pub(crate) fn replay(&self, sink: &impl MidiSink) {
+        for (channel, state) in self.channels.iter().enumerate() {
+            let channel = U4::new_masked(channel as u8);
+            send_cc(sink, channel, RESET_ALL_CONTROLLERS, U7::default());
+            state.replay(channel, sink);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RecordingSink;
+
+    fn cc(channel: u8, controller: u8, value: u8) -> MidiMessage {
+        MidiMessage::ControlChange {
+            channel: U4::new(channel).unwrap(),
+            controller: U7::new(controller).unwrap(),
+            value: U7::new(value).unwrap(),
+        }
+    }
+
+    #[test]
+    fn replays_last_state_in_order() {
+        let mut chase = ChaseState::new();
+        let program = MidiMessage::ProgramChange {
+            channel: U4::new(2).unwrap(),
+            program: U7::new(40).unwrap(),
+        };
+        let bend = MidiMessage::PitchBend {
+            channel: U4::new(2).unwrap(),
+            value: U14::new(0x1000).unwrap(),
+        };
+        for message in &[
+            cc(2, 7, 50),
+            program,
+            cc(2, 0, 1),
+            cc(2, 7, 100),
+            // Pitch bend range of 12 semitones
+            cc(2, RPN_MSB, 0),
+            cc(2, RPN_LSB, 0),
+            cc(2, DATA_ENTRY_MSB, 12),
+            cc(2, RPN_MSB, 127),
+            cc(2, RPN_LSB, 127),
+            bend,
+        ] {
+            chase.record(message);
+        }
+
+        let sink = RecordingSink::new();
+        chase.replay(&sink);
+        let sent: Vec<_> = sink
+            .calls()
+            .iter()
+            .filter_map(|call| call.message())
+            .filter(|message| *message != cc(2, RESET_ALL_CONTROLLERS, 0))
+            .filter(|message| message.channel() == U4::new(2))
+            .collect();
+
+        assert_eq!(
+            sent,
+            vec![
+                cc(2, 0, 1),
+                program,
+                cc(2, 7, 100),
+                cc(2, RPN_MSB, 0),
+                cc(2, RPN_LSB, 0),
+                cc(2, DATA_ENTRY_MSB, 12),
+                cc(2, RPN_MSB, 127),
+                cc(2, RPN_LSB, 127),
+                bend,
+            ]
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use super::Position;
+use crate::smf::{EventKind, MetaEvent, TextKind};
+use crate::{MidiMessage, MidiSink, U4, U7};
+
+/// The controller RPG Maker uses to mark the start of a loop
+const LOOP_START_CONTROLLER: u8 = 111;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum Region {
+    Range {
+        start: Position,
+        /// `None` for the end of the file
+        end: Option<Position>,
+    },
+    Markers,
+}
+
+/// A part of a file for a [`Player`](super::Player) to repeat, see
+/// [`Player::set_loop`](super::Player::set_loop)
+///
+/// Loops repeat forever unless limited with [`Loop::times`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Loop {
+    pub(super) region: Region,
+    /// Total number of passes, `None` for forever
+    pub(super) count: Option<u32>,
+}
+
+impl Loop {
+    /// Repeats the whole file
+    pub fn file() -> Loop {
+        Loop::starting_at(Position::Ticks(0))
+    }
+
+    /// Repeats from `start` to the end of the file
+    pub fn starting_at(start: Position) -> Loop {
+        Loop {
+            region: Region::Range { start, end: None },
+            count: None,
+        }
+    }
+
+    /// Repeats from `start` up to `end`, for A-B repeat. If `start` isn't
+    /// before `end`, nothing is repeated and playback carries on past `end`.
+    pub fn between(start: Position, end: Position) -> Loop {
+        Loop {
+            region: Region::Range {
+                start,
+                end: Some(end),
+            },
+            count: None,
+        }
+    }
+
+    /// Repeats between loop points marked in the file.
+    ///
+    /// The loop starts at the last `loopStart` marker or, as in RPG Maker,
+    /// controller 111 on any channel. It ends at a `loopEnd` marker or the end
+    /// of the file. Nothing is repeated until a loop start has been played.
+    pub fn markers() -> Loop {
+        Loop {
+            region: Region::Markers,
+            count: None,
+        }
+    }
+
+    /// Plays the looped part `count` times in total instead of forever
+    pub fn times(self, count: u32) -> Loop {
+        Loop {
+            count: Some(count),
+            ..self
+        }
+    }
+}
+
+fn is_marker(kind: &EventKind, name: &str) -> bool {
+    match kind {
+        EventKind::Meta(MetaEvent::Text(TextKind::Marker, text)) => String::from_utf8_lossy(text)
+            .trim()
+            .eq_ignore_ascii_case(name),
+        _ => false,
+    }
+}
+
+pub(super) fn is_loop_start(kind: &EventKind) -> bool {
+    match kind {
+        EventKind::Midi(MidiMessage::ControlChange { controller, .. }) => {
+            controller.get() == LOOP_START_CONTROLLER
+        }
+        kind => is_marker(kind, "loopStart"),
+    }
+}
+
+pub(super) fn is_loop_end(kind: &EventKind) -> bool {
+    is_marker(kind, "loopEnd")
+}
+
+/// The notes currently on, one bit per key for each channel
+pub(super) struct HeldNotes([u128; 16]);
+
+impl HeldNotes {
+    pub(super) fn new() -> Self {
+        HeldNotes([0; 16])
+    }
+
+    pub(super) fn track(&mut self, message: &MidiMessage) {
+        match *message {
+            MidiMessage::NoteOn { channel, key, .. } => {
+                self.0[channel.get() as usize] |= 1 << key.get();
+            }
+            // Includes note ons with zero velocity
+            MidiMessage::NoteOff { channel, key, .. } => {
+                self.0[channel.get() as usize] &= !(1 << key.get());
+            }
+            _ => {}
+        }
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.0 = [0; 16];
+    }
+
+    /// Sends a note off for every held note
+    pub(super) fn release(&mut self, sink: &impl MidiSink) {
+        for (channel, keys) in self.0.iter().enumerate() {
+            for key in (0..128).filter(|key| keys & (1 << key) != 0) {
+                sink.send(MidiMessage::NoteOff {
+                    channel: U4::new_masked(channel as u8),
+                    key: U7::new_masked(key),
+                    velocity: U7::default(),
+                });
+            }
+        }
+        self.clear();
+    }
+}
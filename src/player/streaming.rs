@@ -0,0 +1,321 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::Arc;
+
+use super::{EventSource, TimedEvent};
+use crate::smf::{read_chunk_header, read_header, Format, Header, TrackReader};
+use crate::SmfError;
+
+/// The most bytes buffered per track
+const MAX_TRACK_BUFFER_SIZE: usize = 16 * 1024;
+
+/// The fewest bytes buffered per track, so that files with many tracks still
+/// read in reasonably sized blocks
+const MIN_TRACK_BUFFER_SIZE: usize = 256;
+
+/// The bytes buffered across all tracks. Per track buffers shrink as the
+/// number of tracks grows, so memory use stays around this even for files
+/// with tens of thousands of tracks.
+const TOTAL_BUFFER_SIZE: usize = 16 * 1024 * 1024;
+
+/// The buffer size for each of `tracks` tracks read at the same time
+fn track_buffer_size(tracks: usize) -> usize {
+    (TOTAL_BUFFER_SIZE / tracks.max(1)).clamp(MIN_TRACK_BUFFER_SIZE, MAX_TRACK_BUFFER_SIZE)
+}
+
+/// Reads a range of a file through its own buffer, using positional reads so
+/// every track can share a single file handle
+struct ChunkReader {
+    file: Arc<File>,
+    pos: u64,
+    end: u64,
+    buf: Box<[u8]>,
+    buf_pos: usize,
+    buf_len: usize,
+}
+
+impl ChunkReader {
+    fn new(file: Arc<File>, start: u64, len: u64, buffer_size: usize) -> Self {
+        // No point buffering more than the whole chunk
+        let buffer_size = buffer_size.min(len as usize);
+        ChunkReader {
+            file,
+            pos: start,
+            end: start + len,
+            buf: vec![0; buffer_size].into_boxed_slice(),
+            buf_pos: 0,
+            buf_len: 0,
+        }
+    }
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.buf_pos == self.buf_len {
+            let want = (self.end - self.pos).min(self.buf.len() as u64) as usize;
+            if want == 0 {
+                return Ok(0);
+            }
+            let read = read_at(&self.file, &mut self.buf[..want], self.pos)?;
+            if read == 0 {
+                return Ok(0);
+            }
+            self.pos += read as u64;
+            self.buf_pos = 0;
+            self.buf_len = read;
+        }
+        let n = out.len().min(self.buf_len - self.buf_pos);
+        out[..n].copy_from_slice(&self.buf[self.buf_pos..self.buf_pos + n]);
+        self.buf_pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+/// A track being read, along with its next event
+struct TrackCursor {
+    reader: TrackReader<ChunkReader>,
+    tick: u64,
+    next: Option<TimedEvent>,
+}
+
+impl TrackCursor {
+    fn new(
+        file: Arc<File>,
+        (start, len): (u64, u64),
+        offset: u64,
+        buffer_size: usize,
+    ) -> Result<Self, SmfError> {
+        let mut cursor = TrackCursor {
+            reader: TrackReader::new(ChunkReader::new(file, start, len, buffer_size)),
+            tick: offset,
+            next: None,
+        };
+        cursor.fill()?;
+        Ok(cursor)
+    }
+
+    fn fill(&mut self) -> Result<(), SmfError> {
+        self.next = match self.reader.next_event()? {
+            Some(event) => {
+                self.tick += event.delta as u64;
+                Some(TimedEvent {
+                    tick: self.tick,
+                    kind: event.kind,
+                })
+            }
+            None => None,
+        };
+        Ok(())
+    }
+}
+
+/// Reads a file's tracks lazily from disk and merges them with a heap
+pub(crate) struct StreamingSource {
+    file: Arc<File>,
+    header: Header,
+    chunks: Vec<(u64, u64)>,
+    /// Bytes buffered for each track being read
+    buffer_size: usize,
+    cursors: Vec<TrackCursor>,
+    heap: BinaryHeap<Reverse<(u64, usize)>>,
+    /// For format 2, the track that will be played after the current one.
+    /// Only one track is read at a time.
+    next_track: usize,
+    current: Option<TimedEvent>,
+}
+
+impl StreamingSource {
+    pub(crate) fn open(path: &Path) -> Result<Self, SmfError> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let header = read_header(&mut reader)?;
+
+        // Only the chunk headers are read here, the bodies are skipped
+        let mut chunks = Vec::new();
+        while chunks.len() < header.track_count as usize {
+            let (id, len) = match read_chunk_header(&mut reader)? {
+                Some(chunk) => chunk,
+                None => break,
+            };
+            let start = reader.stream_position()?;
+            if &id == b"MTrk" {
+                chunks.push((start, len as u64));
+            }
+            reader.seek(SeekFrom::Start(start + len as u64))?;
+        }
+
+        let buffer_size = match header.format {
+            Format::Sequential => MAX_TRACK_BUFFER_SIZE,
+            _ => track_buffer_size(chunks.len()),
+        };
+        let mut source = StreamingSource {
+            file: Arc::new(reader.into_inner()),
+            header,
+            chunks,
+            buffer_size,
+            cursors: Vec::new(),
+            heap: BinaryHeap::new(),
+            next_track: 0,
+            current: None,
+        };
+        source.rewind()?;
+        Ok(source)
+    }
+
+    pub(crate) fn header(&self) -> Header {
+        self.header
+    }
+
+    fn start_track(&mut self, index: usize) -> Result<(), SmfError> {
+        let cursor = TrackCursor::new(self.file.clone(), self.chunks[index], 0, self.buffer_size)?;
+        if let Some(event) = &cursor.next {
+            self.heap.push(Reverse((event.tick, self.cursors.len())));
+        }
+        self.cursors.push(cursor);
+        Ok(())
+    }
+
+    /// Starts the next format 2 track that has any events, replacing the
+    /// current one
+    fn start_next_sequential(&mut self, mut offset: u64) -> Result<(), SmfError> {
+        while self.next_track < self.chunks.len() {
+            let cursor = TrackCursor::new(
+                self.file.clone(),
+                self.chunks[self.next_track],
+                offset,
+                self.buffer_size,
+            )?;
+            self.next_track += 1;
+            offset = cursor.tick;
+            self.cursors.clear();
+            if let Some(event) = &cursor.next {
+                self.heap.push(Reverse((event.tick, 0)));
+                self.cursors.push(cursor);
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves the earliest event of any track into `current`
+    fn pop_next(&mut self) -> Result<(), SmfError> {
+        let Reverse((_, index)) = match self.heap.pop() {
+            Some(entry) => entry,
+            None => return Ok(()),
+        };
+        let cursor = &mut self.cursors[index];
+        self.current = cursor.next.take();
+        cursor.fill()?;
+
+        match &cursor.next {
+            Some(event) => self.heap.push(Reverse((event.tick, index))),
+            // Format 2 tracks are independent patterns played one after another
+            None if self.header.format == Format::Sequential => {
+                let offset = cursor.tick;
+                self.start_next_sequential(offset)?;
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    fn start_all(&mut self) -> Result<(), SmfError> {
+        if self.header.format == Format::Sequential {
+            self.next_track = 0;
+            self.start_next_sequential(0)
+        } else {
+            (0..self.chunks.len()).try_for_each(|index| self.start_track(index))
+        }
+    }
+
+    /// Leaves the source at its end, so an error is only reported once
+    fn clear(&mut self) {
+        self.cursors.clear();
+        self.heap.clear();
+        self.current = None;
+    }
+}
+
+impl EventSource for StreamingSource {
+    fn peek(&mut self) -> Result<Option<&TimedEvent>, SmfError> {
+        if self.current.is_none() {
+            if let Err(e) = self.pop_next() {
+                self.clear();
+                return Err(e);
+            }
+        }
+        Ok(self.current.as_ref())
+    }
+
+    fn advance(&mut self) {
+        self.current = None;
+    }
+
+    fn rewind(&mut self) -> Result<(), SmfError> {
+        self.clear();
+        let result = self.start_all();
+        if result.is_err() {
+            self.clear();
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::player::MemorySource;
+    use crate::smf::Smf;
+
+    fn collect(source: &mut dyn EventSource) -> Vec<TimedEvent> {
+        let mut events = Vec::new();
+        while let Some(event) = source.peek().unwrap() {
+            events.push(event.clone());
+            source.advance();
+        }
+        events
+    }
+
+    #[test]
+    fn matches_in_memory_merge() {
+        #[rustfmt::skip]
+        let bytes = [
+            b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 1, 0, 3, 0, 96,
+            b'M', b'T', b'r', b'k', 0, 0, 0, 14,
+            0x00, 0x90, 0x3C, 0x40,
+            0x60, 0x3C, 0x00,
+            0x00, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20,
+            b'X', b'f', b'o', b'o', 0, 0, 0, 1, 0xAA,
+            b'M', b'T', b'r', b'k', 0, 0, 0, 0,
+            b'M', b'T', b'r', b'k', 0, 0, 0, 11,
+            0x30, 0x91, 0x40, 0x40,
+            0x30, 0x40, 0x00,
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let path =
+            std::env::temp_dir().join(format!("kdmapi-streaming-{}.mid", std::process::id()));
+        std::fs::write(&path, bytes).unwrap();
+
+        let mut streaming = StreamingSource::open(&path).unwrap();
+        let mut memory = MemorySource::new(&Smf::parse(&bytes).unwrap());
+        let expected = collect(&mut memory);
+        assert_eq!(expected.len(), 6);
+        assert_eq!(collect(&mut streaming), expected);
+
+        streaming.rewind().unwrap();
+        assert_eq!(collect(&mut streaming), expected);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,175 @@
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::sysex::validate_sysex;
+use crate::{MidiMessage, MidiSink, SysExError};
+
+/// A call made on a [`RecordingSink`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordedCall {
+    /// `send_direct_data`, or [`MidiSink::send_short`]
+    DirectData(u32),
+    /// `send_direct_data_no_buf`, or [`MidiSink::send_short_no_buf`]
+    DirectDataNoBuf(u32),
+    /// `send_sysex`, or [`MidiSink::send_long`]
+    LongData(Vec<u8>),
+    /// `reset`, or [`MidiSink::reset`]
+    Reset,
+}
+
+impl RecordedCall {
+    /// Decodes the message of a short data call, `None` for other calls or
+    /// data that doesn't decode
+    pub fn message(&self) -> Option<MidiMessage> {
+        match *self {
+            RecordedCall::DirectData(data) | RecordedCall::DirectDataNoBuf(data) => {
+                MidiMessage::from_kdmapi_u32(data).ok()
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A call made on a [`RecordingSink`], along with when it was made
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedEvent {
+    /// Time since the sink was created, from a monotonic clock
+    pub time: Duration,
+    pub call: RecordedCall,
+}
+
+/// An in-memory sink that records every call made on it, for asserting exact
+/// event sequences and timing without loading OmniMIDI.
+///
+/// Offers the same methods as [`KDMAPIStream`](crate::KDMAPIStream) and
+/// implements [`MidiSink`]. Wrap it in an `Arc` to hand it to code that takes
+/// ownership of its sink while still being able to inspect it.
+#[derive(Debug)]
+pub struct RecordingSink {
+    start: Instant,
+    events: Mutex<Vec<RecordedEvent>>,
+}
+
+impl RecordingSink {
+    pub fn new() -> Self {
+        RecordingSink {
+            start: Instant::now(),
+            events: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Records a `ResetKDMAPIStream` call
+    pub fn reset(&self) {
+        self.record(RecordedCall::Reset);
+    }
+
+    /// Records a `SendDirectData` call
+    pub fn send_direct_data(&self, data: u32) -> u32 {
+        self.record(RecordedCall::DirectData(data));
+        0
+    }
+
+    /// Records a `SendDirectDataNoBuf` call
+    pub fn send_direct_data_no_buf(&self, data: u32) -> u32 {
+        self.record(RecordedCall::DirectDataNoBuf(data));
+        0
+    }
+
+    /// Validates and records a SysEx message the same way
+    /// [`KDMAPIStream::send_sysex`](crate::KDMAPIStream::send_sysex) would
+    /// send it
+    pub fn send_sysex(&self, data: &[u8]) -> Result<(), SysExError> {
+        validate_sysex(data)?;
+        self.record(RecordedCall::LongData(data.to_vec()));
+        Ok(())
+    }
+
+    /// Returns a copy of every recorded event, in the order they were made
+    pub fn events(&self) -> Vec<RecordedEvent> {
+        self.lock().clone()
+    }
+
+    /// Returns every recorded call without its timestamp
+    pub fn calls(&self) -> Vec<RecordedCall> {
+        self.lock().iter().map(|e| e.call.clone()).collect()
+    }
+
+    /// Removes and returns every recorded event
+    pub fn take_events(&self) -> Vec<RecordedEvent> {
+        std::mem::take(&mut *self.lock())
+    }
+
+    /// The number of recorded events
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Returns true if nothing has been recorded
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    fn record(&self, call: RecordedCall) {
+        let mut events = self.lock();
+        // Taking the timestamp under the lock keeps them in order
+        let time = self.start.elapsed();
+        events.push(RecordedEvent { time, call });
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<RecordedEvent>> {
+        // A panic while recording can't leave the Vec in a bad state
+        self.events.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Default for RecordingSink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MidiSink for RecordingSink {
+    fn send_short(&self, data: u32) {
+        self.send_direct_data(data);
+    }
+
+    fn send_short_no_buf(&self, data: u32) {
+        self.send_direct_data_no_buf(data);
+    }
+
+    fn send_long(&self, data: &[u8]) -> Result<(), SysExError> {
+        self.send_sysex(data)
+    }
+
+    fn reset(&self) {
+        RecordingSink::reset(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_calls_in_order() {
+        let sink = RecordingSink::new();
+        sink.send_direct_data(0x7F4090);
+        sink.send_short_no_buf(0x004080);
+        assert!(sink.send_long(&[0xF0, 0x7E, 0xF7]).is_ok());
+        assert!(sink.send_long(&[0xF0, 0x7E]).is_err());
+        MidiSink::reset(&sink);
+
+        assert_eq!(
+            sink.calls(),
+            vec![
+                RecordedCall::DirectData(0x7F4090),
+                RecordedCall::DirectDataNoBuf(0x004080),
+                RecordedCall::LongData(vec![0xF0, 0x7E, 0xF7]),
+                RecordedCall::Reset,
+            ]
+        );
+        let events = sink.take_events();
+        assert!(events.windows(2).all(|w| w[0].time <= w[1].time));
+        assert!(sink.is_empty());
+    }
+}
@@ -0,0 +1,168 @@
+use std::ffi::c_void;
+use std::ops::RangeInclusive;
+
+use crate::{DriverSettingError, KDMAPIStream};
+
+const OM_SET: u32 = 0;
+const OM_GET: u32 = 1;
+
+pub(crate) type DriverSettingsFn = unsafe extern "C" fn(u32, u32, *mut c_void, u32) -> bool;
+
+/// A runtime setting of OmniMIDI, read and changed through
+/// [`DriverSettings`]
+///
+/// Every setting is a `u32`. Flags are 0 or 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DriverSetting {
+    /// `OM_MAXVOICES`, the voice limit, from 1 to 100000
+    MaxVoices,
+    /// `OM_AUDIOFREQ`, the output sample rate in Hz, from 8000 to 384000
+    AudioFrequency,
+    /// `OM_BUFFERLENGTH`, the length of the audio buffer, from 1 to 1000
+    BufferLength,
+    /// `OM_OUTPUTVOLUME`, the output volume, from 0 to 10000 for full volume
+    OutputVolume,
+    /// `OM_MAXRENDERINGTIME`, the CPU limit in percent, or 0 for no limit
+    MaxRenderingTime,
+    /// `OM_DISABLEFADEOUT`
+    DisableFadeout,
+    /// `OM_DONOTMISSNOTES`
+    DoNotMissNotes,
+    /// `OM_FULLVELOCITY`
+    FullVelocity,
+    /// `OM_IGNORESYSEX`
+    IgnoreSysEx,
+    /// `OM_IGNORESYSRESET`
+    IgnoreSysReset,
+    /// `OM_LIMITRANGETO88`
+    LimitRangeTo88,
+    /// `OM_MONORENDERING`
+    MonoRendering,
+    /// `OM_SINCINTER`
+    SincInterpolation,
+}
+
+impl DriverSetting {
+    /// The `OM_*` ID passed to `DriverSettings`
+    pub const fn id(self) -> u32 {
+        match self {
+            DriverSetting::MaxVoices => 0x10026,
+            DriverSetting::AudioFrequency => 0x10018,
+            DriverSetting::BufferLength => 0x10020,
+            DriverSetting::OutputVolume => 0x10024,
+            DriverSetting::MaxRenderingTime => 0x10021,
+            DriverSetting::DisableFadeout => 0x10002,
+            DriverSetting::DoNotMissNotes => 0x10003,
+            DriverSetting::FullVelocity => 0x10005,
+            DriverSetting::IgnoreSysEx => 0x10008,
+            DriverSetting::IgnoreSysReset => 0x10009,
+            DriverSetting::LimitRangeTo88 => 0x10010,
+            DriverSetting::MonoRendering => 0x10012,
+            DriverSetting::SincInterpolation => 0x10015,
+        }
+    }
+
+    /// Returns true if the setting is 0 or 1
+    pub const fn is_flag(self) -> bool {
+        !matches!(
+            self,
+            DriverSetting::MaxVoices
+                | DriverSetting::AudioFrequency
+                | DriverSetting::BufferLength
+                | DriverSetting::OutputVolume
+                | DriverSetting::MaxRenderingTime
+        )
+    }
+
+    /// The values the setting accepts
+    pub fn range(self) -> RangeInclusive<u32> {
+        match self {
+            DriverSetting::MaxVoices => 1..=100_000,
+            DriverSetting::AudioFrequency => 8_000..=384_000,
+            DriverSetting::BufferLength => 1..=1_000,
+            DriverSetting::OutputVolume => 0..=10_000,
+            DriverSetting::MaxRenderingTime => 0..=100,
+            flag => {
+                debug_assert!(flag.is_flag());
+                0..=1
+            }
+        }
+    }
+}
+
+/// Access to OmniMIDI's runtime settings, see [`KDMAPIStream::settings`]
+pub struct DriverSettings<'a> {
+    stream: &'a KDMAPIStream,
+}
+
+impl DriverSettings<'_> {
+    /// Calls `DriverSettings` with `OM_GET`
+    ///
+    /// Returns [`DriverSettingError::Unsupported`] if the driver doesn't
+    /// export it.
+    pub fn get(&self, setting: DriverSetting) -> Result<u32, DriverSettingError> {
+        let mut value = 0u32;
+        self.call(setting, OM_GET, &mut value)?;
+        Ok(value)
+    }
+
+    /// Calls `DriverSettings` with `OM_SET`, after checking that `value` is
+    /// in [`DriverSetting::range`]
+    ///
+    /// Returns [`DriverSettingError::Unsupported`] if the driver doesn't
+    /// export it.
+    pub fn set(&self, setting: DriverSetting, mut value: u32) -> Result<(), DriverSettingError> {
+        if !setting.range().contains(&value) {
+            return Err(DriverSettingError::OutOfRange { setting, value });
+        }
+        self.call(setting, OM_SET, &mut value)
+    }
+
+    /// Reads a flag setting
+    pub fn is_enabled(&self, setting: DriverSetting) -> Result<bool, DriverSettingError> {
+        Ok(self.get(setting)? != 0)
+    }
+
+    /// Turns a flag setting on or off
+    pub fn set_enabled(
+        &self,
+        setting: DriverSetting,
+        enabled: bool,
+    ) -> Result<(), DriverSettingError> {
+        self.set(setting, enabled as u32)
+    }
+
+    fn call(
+        &self,
+        setting: DriverSetting,
+        mode: u32,
+        value: &mut u32,
+    ) -> Result<(), DriverSettingError> {
+        let driver_settings = self
+            .stream
+            .binds
+            .driver_settings
+            .ok_or(DriverSettingError::Unsupported)?;
+        let ok = unsafe {
+            driver_settings(
+                setting.id(),
+                mode,
+                value as *mut u32 as *mut c_void,
+                std::mem::size_of::<u32>() as u32,
+            )
+        };
+        if ok {
+            Ok(())
+        } else {
+            Err(DriverSettingError::Driver(setting))
+        }
+    }
+}
+
+impl KDMAPIStream {
+    /// Returns access to the driver's runtime settings, e.g. to raise the
+    /// voice limit for dense files
+    pub fn settings(&self) -> DriverSettings<'_> {
+        DriverSettings { stream: self }
+    }
+}
@@ -0,0 +1,115 @@
+use std::sync::Arc;
+
+use crate::{KDMAPIStream, MidiMessage, SysExError, U4, U7};
+
+/// A destination for MIDI data
+///
+/// Implemented by [`KDMAPIStream`], so code that drives a stream can be
+/// written against this trait and run with any other output or a test double.
+pub trait MidiSink {
+    /// Sends a short message in the `SendDirectData` DWORD layout
+    fn send_short(&self, data: u32);
+
+    /// Sends a short message, bypassing any buffering the sink does. Defaults
+    /// to [`MidiSink::send_short`].
+    fn send_short_no_buf(&self, data: u32) {
+        self.send_short(data)
+    }
+
+    /// Sends a complete SysEx message, including the `F0` and `F7` bytes
+    fn send_long(&self, data: &[u8]) -> Result<(), SysExError>;
+
+    /// Resets the output
+    fn reset(&self);
+
+    /// Encodes and sends a typed message
+    fn send(&self, message: MidiMessage) {
+        self.send_short(message.to_kdmapi_u32())
+    }
+
+    /// Sends All Sound Off and All Notes Off to every channel
+    fn all_notes_off(&self) {
+        for channel in 0..16 {
+            let channel = U4::new_masked(channel);
+            for &controller in &[120, 123] {
+                self.send(MidiMessage::ControlChange {
+                    channel,
+                    controller: U7::new_masked(controller),
+                    value: U7::new_masked(0),
+                });
+            }
+        }
+    }
+}
+
+impl MidiSink for KDMAPIStream {
+    fn send_short(&self, data: u32) {
+        self.send_direct_data(data);
+    }
+
+    fn send_short_no_buf(&self, data: u32) {
+        self.send_direct_data_no_buf(data);
+    }
+
+    fn send_long(&self, data: &[u8]) -> Result<(), SysExError> {
+        self.send_sysex(data)
+    }
+
+    fn reset(&self) {
+        KDMAPIStream::reset(self)
+    }
+}
+
+impl<S: MidiSink + ?Sized> MidiSink for &S {
+    fn send_short(&self, data: u32) {
+        (**self).send_short(data)
+    }
+
+    fn send_short_no_buf(&self, data: u32) {
+        (**self).send_short_no_buf(data)
+    }
+
+    fn send_long(&self, data: &[u8]) -> Result<(), SysExError> {
+        (**self).send_long(data)
+    }
+
+    fn reset(&self) {
+        (**self).reset()
+    }
+}
+
+impl<S: MidiSink + ?Sized> MidiSink for Arc<S> {
+    fn send_short(&self, data: u32) {
+        (**self).send_short(data)
+    }
+
+    fn send_short_no_buf(&self, data: u32) {
+        (**self).send_short_no_buf(data)
+    }
+
+    fn send_long(&self, data: &[u8]) -> Result<(), SysExError> {
+        (**self).send_long(data)
+    }
+
+    fn reset(&self) {
+        (**self).reset()
+    }
+}
+
+impl<S: MidiSink + ?Sized> MidiSink for Box<S> {
+    fn send_short(&self, data: u32) {
+        (**self).send_short(data)
+    }
+
+    fn send_short_no_buf(&self, data: u32) {
+        (**self).send_short_no_buf(data)
+    }
+
+    fn send_long(&self, data: &[u8]) -> Result<(), SysExError> {
+        (**self).send_long(data)
+    }
+
+    fn reset(&self) {
+        (**self).reset()
+    }
+}
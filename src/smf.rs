@@ -0,0 +1,613 @@
+//! Standard MIDI File reading
+//!
+//! Supports formats 0, 1 and 2, running status, meta events, SysEx and escape
+//! events, and both metrical (PPQ) and timecode (SMPTE) divisions.
+
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+use crate::{MidiMessage, MidiSink, SmfError, SysExError};
+
+/// The format field of the file header
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Format {
+    /// Format 0, a single multi-channel track
+    SingleTrack,
+    /// Format 1, simultaneous tracks played in parallel
+    Parallel,
+    /// Format 2, independent single-track patterns played one after another
+    Sequential,
+}
+
+/// SMPTE frame rate of a timecode division
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Fps {
+    Fps24,
+    Fps25,
+    /// 29.97 frames per second, drop frame
+    Fps29,
+    Fps30,
+}
+
+impl Fps {
+    /// The nominal number of frames per second, as stored in the file
+    pub fn frames(self) -> u8 {
+        match self {
+            Fps::Fps24 => 24,
+            Fps::Fps25 => 25,
+            Fps::Fps29 => 29,
+            Fps::Fps30 => 30,
+        }
+    }
+
+    /// The actual number of frames per second
+    pub fn as_f64(self) -> f64 {
+        match self {
+            Fps::Fps29 => 30000.0 / 1001.0,
+            fps => fps.frames() as f64,
+        }
+    }
+}
+
+/// How ticks relate to time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Timing {
+    /// Ticks per quarter note, so the length of a tick follows the tempo
+    Metrical(u16),
+    /// Ticks per SMPTE frame, so ticks have a fixed length
+    Timecode { fps: Fps, subframes: u8 },
+}
+
+/// The contents of the `MThd` chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Header {
+    pub format: Format,
+    /// The number of tracks the header declares. The file may contain fewer.
+    pub track_count: u16,
+    pub timing: Timing,
+}
+
+/// The kind of a text meta event
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TextKind {
+    Text,
+    Copyright,
+    TrackName,
+    InstrumentName,
+    Lyric,
+    Marker,
+    CuePoint,
+    ProgramName,
+    DeviceName,
+}
+
+/// A meta event. Only appears in files, never sent to the driver.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum MetaEvent {
+    /// `None` if the event has no data, meaning the track's position is used
+    SequenceNumber(Option<u16>),
+    /// Text, usually but not always ASCII or UTF-8
+    Text(TextKind, Vec<u8>),
+    ChannelPrefix(u8),
+    Port(u8),
+    EndOfTrack,
+    /// Microseconds per quarter note
+    Tempo(u32),
+    SmpteOffset {
+        hours: u8,
+        minutes: u8,
+        seconds: u8,
+        frames: u8,
+        subframes: u8,
+    },
+    TimeSignature {
+        numerator: u8,
+        /// The denominator as a power of two, so 2 means a quarter note
+        denominator: u8,
+        clocks_per_click: u8,
+        thirty_seconds_per_quarter: u8,
+    },
+    KeySignature {
+        /// Negative for flats, positive for sharps
+        sharps: i8,
+        minor: bool,
+    },
+    SequencerSpecific(Vec<u8>),
+    Unknown {
+        kind: u8,
+        data: Vec<u8>,
+    },
+}
+
+/// The contents of a track event
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    Midi(MidiMessage),
+    /// A SysEx message, including the leading `F0`. Only ends with `F7` if the
+    /// message isn't split across several events.
+    SysEx(Vec<u8>),
+    /// Arbitrary bytes to be sent as they are
+    Escape(Vec<u8>),
+    Meta(MetaEvent),
+}
+
+impl EventKind {
+    /// Sends the event to `sink`. Meta events are ignored, escape events are
+    /// sent as SysEx if they start with `F0` and as a short message if they
+    /// are at most three bytes long.
+    pub fn send_to<S: MidiSink + ?Sized>(&self, sink: &S) -> Result<(), SysExError> {
+        match self {
+            EventKind::Midi(message) => sink.send(*message),
+            EventKind::SysEx(data) => sink.send_long(data)?,
+            EventKind::Escape(data) if data.first() == Some(&0xF0) => sink.send_long(data)?,
+            EventKind::Escape(data) if !data.is_empty() && data.len() <= 3 => {
+                let packed = data
+                    .iter()
+                    .rev()
+                    .fold(0u32, |packed, &byte| packed << 8 | byte as u32);
+                sink.send_short(packed)
+            }
+            EventKind::Escape(_) | EventKind::Meta(_) => {}
+        }
+        Ok(())
+    }
+}
+
+/// An event in a track, with its time relative to the previous event
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TrackEvent {
+    pub delta: u32,
+    pub kind: EventKind,
+}
+
+/// A fully loaded Standard MIDI File
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Smf {
+    pub header: Header,
+    pub tracks: Vec<Vec<TrackEvent>>,
+}
+
+impl Smf {
+    /// Parses a file that is already in memory
+    pub fn parse(mut bytes: &[u8]) -> Result<Smf, SmfError> {
+        Smf::read(&mut bytes)
+    }
+
+    /// Reads and parses a file from disk
+    pub fn open(path: impl AsRef<Path>) -> Result<Smf, SmfError> {
+        Smf::read(BufReader::new(File::open(path)?))
+    }
+
+    /// Reads and parses a file from `reader`
+    pub fn read<R: Read>(mut reader: R) -> Result<Smf, SmfError> {
+        let header = read_header(&mut reader)?;
+
+        let mut tracks = Vec::with_capacity(header.track_count as usize);
+        while tracks.len() < header.track_count as usize {
+            let (id, len) = match read_chunk_header(&mut reader)? {
+                Some(chunk) => chunk,
+                // Tolerate files that declare more tracks than they contain
+                None => break,
+            };
+            let mut body = (&mut reader).take(len as u64);
+            if &id == b"MTrk" {
+                let events = TrackReader::new(&mut body).collect::<Result<_, _>>()?;
+                tracks.push(events);
+            }
+            // Skip unread bytes, such as unknown chunks or data after the end of track
+            io::copy(&mut body, &mut io::sink())?;
+        }
+
+        Ok(Smf { header, tracks })
+    }
+}
+
+impl Smf {
+    /// Calls `f` with every event and its absolute tick, track by track.
+    /// Format 2 tracks are offset so that each one starts where the previous
+    /// one ended.
+    pub(crate) fn for_each_timed(&self, mut f: impl FnMut(u64, &EventKind)) {
+        let mut offset = 0;
+        for track in &self.tracks {
+            let mut tick = offset;
+            for event in track {
+                tick += event.delta as u64;
+                f(tick, &event.kind);
+            }
+            if self.header.format == Format::Sequential {
+                offset = tick;
+            }
+        }
+    }
+}
+
+/// Reads the `MThd` chunk from the start of a file
+pub fn read_header<R: Read>(mut reader: R) -> Result<Header, SmfError> {
+    let (id, len) = read_chunk_header(&mut reader)?.ok_or(SmfError::UnexpectedEof)?;
+    if &id != b"MThd" || len < 6 {
+        return Err(SmfError::NotSmf);
+    }
+    let mut data = [0; 6];
+    reader.read_exact(&mut data).map_err(eof)?;
+    io::copy(&mut (&mut reader).take(len as u64 - 6), &mut io::sink())?;
+
+    let format = match u16::from_be_bytes([data[0], data[1]]) {
+        0 => Format::SingleTrack,
+        1 => Format::Parallel,
+        2 => Format::Sequential,
+        format => return Err(SmfError::UnsupportedFormat(format)),
+    };
+    let track_count = u16::from_be_bytes([data[2], data[3]]);
+    let timing = if data[4] & 0x80 == 0 {
+        match u16::from_be_bytes([data[4], data[5]]) {
+            0 => return Err(SmfError::InvalidTiming),
+            ppq => Timing::Metrical(ppq),
+        }
+    } else {
+        let fps = match (data[4] as i8).wrapping_neg() {
+            24 => Fps::Fps24,
+            25 => Fps::Fps25,
+            29 => Fps::Fps29,
+            30 => Fps::Fps30,
+            _ => return Err(SmfError::InvalidTiming),
+        };
+        match data[5] {
+            0 => return Err(SmfError::InvalidTiming),
+            subframes => Timing::Timecode { fps, subframes },
+        }
+    };
+
+    Ok(Header {
+        format,
+        track_count,
+        timing,
+    })
+}
+
+/// Reads a chunk's type and length, `None` if `reader` is at its end
+pub(crate) fn read_chunk_header<R: Read>(
+    mut reader: R,
+) -> Result<Option<([u8; 4], u32)>, SmfError> {
+    let mut id = [0; 4];
+    if read_or_eof(&mut reader, &mut id[..1])? {
+        return Ok(None);
+    }
+    reader.read_exact(&mut id[1..]).map_err(eof)?;
+    let mut len = [0; 4];
+    reader.read_exact(&mut len).map_err(eof)?;
+    Ok(Some((id, u32::from_be_bytes(len))))
+}
+
+/// Decodes the events of a single `MTrk` chunk one at a time
+///
+/// The reader should be limited to the body of the chunk, e.g. with
+/// [`Read::take`]. Reading stops at the End of Track event, or at the end of
+/// the reader if the track is missing one.
+///
+/// Running status is kept across meta and SysEx events, as many files in the
+/// wild rely on it.
+pub struct TrackReader<R> {
+    reader: R,
+    running_status: Option<u8>,
+    finished: bool,
+}
+
+impl<R: Read> TrackReader<R> {
+    pub fn new(reader: R) -> Self {
+        TrackReader {
+            reader,
+            running_status: None,
+            finished: false,
+        }
+    }
+
+    /// Reads the next event, `None` once the track has ended
+    pub fn next_event(&mut self) -> Result<Option<TrackEvent>, SmfError> {
+        if self.finished {
+            return Ok(None);
+        }
+        let result = self.read_event();
+        if !matches!(result, Ok(Some(_))) {
+            self.finished = true;
+        }
+        result
+    }
+
+    fn read_event(&mut self) -> Result<Option<TrackEvent>, SmfError> {
+        let mut first = [0];
+        if read_or_eof(&mut self.reader, &mut first)? {
+            return Ok(None);
+        }
+        let delta = read_var_len_from(&mut self.reader, first[0])?;
+
+        let kind = match self.read_u8()? {
+            0xFF => {
+                let kind = self.read_u8()?;
+                let data = self.read_var_data()?;
+                EventKind::Meta(parse_meta(kind, data)?)
+            }
+            0xF0 => {
+                let len = self.read_var_len()?;
+                let mut data = Vec::with_capacity(len as usize + 1);
+                data.push(0xF0);
+                self.read_into(&mut data, len)?;
+                EventKind::SysEx(data)
+            }
+            0xF7 => EventKind::Escape(self.read_var_data()?),
+            status if status & 0x80 != 0 => {
+                self.running_status = Some(status);
+                let data1 = self.read_u8()?;
+                EventKind::Midi(self.read_channel_message(status, data1)?)
+            }
+            data1 => {
+                let status = self.running_status.ok_or(SmfError::MissingStatus)?;
+                EventKind::Midi(self.read_channel_message(status, data1)?)
+            }
+        };
+
+        if kind == EventKind::Meta(MetaEvent::EndOfTrack) {
+            self.finished = true;
+        }
+        Ok(Some(TrackEvent { delta, kind }))
+    }
+
+    fn read_channel_message(&mut self, status: u8, data1: u8) -> Result<MidiMessage, SmfError> {
+        if status >= 0xF0 {
+            return Err(SmfError::InvalidStatus(status));
+        }
+        let data2 = match status & 0xF0 {
+            0xC0 | 0xD0 => 0,
+            _ => self.read_u8()?,
+        };
+        Ok(MidiMessage::from_bytes(status, data1, data2)?)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, SmfError> {
+        let mut byte = [0];
+        self.reader.read_exact(&mut byte).map_err(eof)?;
+        Ok(byte[0])
+    }
+
+    fn read_var_len(&mut self) -> Result<u32, SmfError> {
+        let first = self.read_u8()?;
+        read_var_len_from(&mut self.reader, first)
+    }
+
+    fn read_var_data(&mut self) -> Result<Vec<u8>, SmfError> {
+        let len = self.read_var_len()?;
+        let mut data = Vec::with_capacity(len as usize);
+        self.read_into(&mut data, len)?;
+        Ok(data)
+    }
+
+    fn read_into(&mut self, data: &mut Vec<u8>, len: u32) -> Result<(), SmfError> {
+        let read = (&mut self.reader).take(len as u64).read_to_end(data)?;
+        if read < len as usize {
+            return Err(SmfError::UnexpectedEof);
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Iterator for TrackReader<R> {
+    type Item = Result<TrackEvent, SmfError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_event().transpose()
+    }
+}
+
+fn parse_meta(kind: u8, data: Vec<u8>) -> Result<MetaEvent, SmfError> {
+    let text_kind = match kind {
+        0x01 => Some(TextKind::Text),
+        0x02 => Some(TextKind::Copyright),
+        0x03 => Some(TextKind::TrackName),
+        0x04 => Some(TextKind::InstrumentName),
+        0x05 => Some(TextKind::Lyric),
+        0x06 => Some(TextKind::Marker),
+        0x07 => Some(TextKind::CuePoint),
+        0x08 => Some(TextKind::ProgramName),
+        0x09 => Some(TextKind::DeviceName),
+        _ => None,
+    };
+    if let Some(text_kind) = text_kind {
+        return Ok(MetaEvent::Text(text_kind, data));
+    }
+
+    let need = |len: usize| {
+        if data.len() < len {
+            Err(SmfError::InvalidMeta(kind))
+        } else {
+            Ok(())
+        }
+    };
+
+    match kind {
+        0x00 if data.is_empty() => Ok(MetaEvent::SequenceNumber(None)),
+        0x00 => {
+            need(2)?;
+            Ok(MetaEvent::SequenceNumber(Some(u16::from_be_bytes([
+                data[0], data[1],
+            ]))))
+        }
+        0x20 => {
+            need(1)?;
+            Ok(MetaEvent::ChannelPrefix(data[0]))
+        }
+        0x21 => {
+            need(1)?;
+            Ok(MetaEvent::Port(data[0]))
+        }
+        0x2F => Ok(MetaEvent::EndOfTrack),
+        0x51 => {
+            need(3)?;
+            Ok(MetaEvent::Tempo(u32::from_be_bytes([
+                0, data[0], data[1], data[2],
+            ])))
+        }
+        0x54 => {
+            need(5)?;
+            Ok(MetaEvent::SmpteOffset {
+                hours: data[0],
+                minutes: data[1],
+                seconds: data[2],
+                frames: data[3],
+                subframes: data[4],
+            })
+        }
+        0x58 => {
+            need(4)?;
+            Ok(MetaEvent::TimeSignature {
+                numerator: data[0],
+                denominator: data[1],
+                clocks_per_click: data[2],
+                thirty_seconds_per_quarter: data[3],
+            })
+        }
+        0x59 => {
+            need(2)?;
+            Ok(MetaEvent::KeySignature {
+                sharps: data[0] as i8,
+                minor: data[1] != 0,
+            })
+        }
+        0x7F => Ok(MetaEvent::SequencerSpecific(data)),
+        kind => Ok(MetaEvent::Unknown { kind, data }),
+    }
+}
+
+/// Reads a variable length quantity whose first byte has already been read
+fn read_var_len_from<R: Read>(reader: &mut R, first: u8) -> Result<u32, SmfError> {
+    let mut value = (first & 0x7F) as u32;
+    let mut byte = first;
+    let mut len = 1;
+    while byte & 0x80 != 0 {
+        if len == 4 {
+            return Err(SmfError::InvalidVarLen);
+        }
+        let mut next = [0];
+        reader.read_exact(&mut next).map_err(eof)?;
+        byte = next[0];
+        value = value << 7 | (byte & 0x7F) as u32;
+        len += 1;
+    }
+    Ok(value)
+}
+
+/// Fills `buf`, returning true if the reader was already at its end
+fn read_or_eof<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<bool, SmfError> {
+    loop {
+        match reader.read(buf) {
+            Ok(0) => return Ok(true),
+            Ok(n) => {
+                reader.read_exact(&mut buf[n..]).map_err(eof)?;
+                return Ok(false);
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+fn eof(e: io::Error) -> SmfError {
+    if e.kind() == io::ErrorKind::UnexpectedEof {
+        SmfError::UnexpectedEof
+    } else {
+        SmfError::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{U4, U7};
+
+    fn note_on(channel: u8, key: u8, velocity: u8) -> EventKind {
+        EventKind::Midi(MidiMessage::NoteOn {
+            channel: U4::new(channel).unwrap(),
+            key: U7::new(key).unwrap(),
+            velocity: U7::new(velocity).unwrap(),
+        })
+    }
+
+    #[test]
+    fn parses_format_1_with_running_status() {
+        #[rustfmt::skip]
+        let bytes = [
+            b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 1, 0, 2, 0x01, 0xE0,
+            b'M', b'T', b'r', b'k', 0, 0, 0, 19,
+            0x00, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20,
+            0x00, 0xFF, 0x58, 0x04, 0x03, 0x02, 0x18, 0x08,
+            0x00, 0xFF, 0x2F, 0x00,
+            b'M', b'T', b'r', b'k', 0, 0, 0, 21,
+            0x00, 0x90, 0x3C, 0x40,
+            0x81, 0x00, 0x3E, 0x40,
+            0x10, 0xF0, 0x03, 0x7E, 0x01, 0xF7,
+            0x00, 0x40, 0x00,
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let smf = Smf::parse(&bytes).unwrap();
+
+        assert_eq!(
+            smf.header,
+            Header {
+                format: Format::Parallel,
+                track_count: 2,
+                timing: Timing::Metrical(480),
+            }
+        );
+        assert_eq!(
+            smf.tracks[0][0].kind,
+            EventKind::Meta(MetaEvent::Tempo(500_000))
+        );
+        let track: Vec<_> = smf.tracks[1]
+            .iter()
+            .map(|e| (e.delta, e.kind.clone()))
+            .collect();
+        assert_eq!(
+            track,
+            vec![
+                (0, note_on(0, 0x3C, 0x40)),
+                (128, note_on(0, 0x3E, 0x40)),
+                (16, EventKind::SysEx(vec![0xF0, 0x7E, 0x01, 0xF7])),
+                (
+                    0,
+                    EventKind::Midi(MidiMessage::NoteOff {
+                        channel: U4::new(0).unwrap(),
+                        key: U7::new(0x40).unwrap(),
+                        velocity: U7::new(0).unwrap(),
+                    })
+                ),
+                (0, EventKind::Meta(MetaEvent::EndOfTrack)),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_smpte_division() {
+        let bytes = [b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 0, 0, 0, 0xE7, 40];
+        let header = Smf::parse(&bytes).unwrap().header;
+        assert_eq!(
+            header.timing,
+            Timing::Timecode {
+                fps: Fps::Fps25,
+                subframes: 40
+            }
+        );
+
+        let bytes = [b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 0, 0, 0, 0x80, 40];
+        assert!(matches!(Smf::parse(&bytes), Err(SmfError::InvalidTiming)));
+    }
+
+    #[test]
+    fn rejects_truncated_events() {
+        #[rustfmt::skip]
+        let bytes = [
+            b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 0, 0, 1, 0, 96,
+            b'M', b'T', b'r', b'k', 0, 0, 0, 3,
+            0x00, 0x90, 0x3C,
+        ];
+        assert!(matches!(Smf::parse(&bytes), Err(SmfError::UnexpectedEof)));
+    }
+}
@@ -0,0 +1,241 @@
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{KDMAPIStream, SoundFontError};
+
+/// `wchar_t`, which is UTF-16 on Windows and UTF-32 elsewhere
+#[cfg(windows)]
+pub(crate) type WChar = u16;
+#[cfg(not(windows))]
+pub(crate) type WChar = u32;
+
+pub(crate) type LoadCustomSoundFontsListFn = unsafe extern "C" fn(*const WChar);
+
+/// Encodes `path` as a null terminated wide string
+fn to_wide(path: &Path) -> Result<Vec<WChar>, SoundFontError> {
+    let invalid = || SoundFontError::InvalidPath(path.to_owned());
+
+    #[cfg(windows)]
+    let mut wide: Vec<WChar> = {
+        use std::os::windows::ffi::OsStrExt;
+        path.as_os_str().encode_wide().collect()
+    };
+    #[cfg(not(windows))]
+    let mut wide: Vec<WChar> = path
+        .to_str()
+        .ok_or_else(invalid)?
+        .chars()
+        .map(|c| c as WChar)
+        .collect();
+
+    if wide.contains(&0) {
+        return Err(invalid());
+    }
+    wide.push(0);
+    Ok(wide)
+}
+
+/// Replaces a preset of a SoundFont with another one of the same SoundFont
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PresetRemap {
+    pub source_bank: u16,
+    pub source_preset: u8,
+    pub destination_bank: u16,
+    pub destination_preset: u8,
+}
+
+impl PresetRemap {
+    /// Makes the preset at `destination_bank` and `destination_preset` play
+    /// the SoundFont's preset at `source_bank` and `source_preset`
+    pub const fn new(
+        source_bank: u16,
+        source_preset: u8,
+        destination_bank: u16,
+        destination_preset: u8,
+    ) -> Self {
+        PresetRemap {
+            source_bank,
+            source_preset,
+            destination_bank,
+            destination_preset,
+        }
+    }
+}
+
+/// A SoundFont in a [`SoundFontList`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SoundFontEntry {
+    path: PathBuf,
+    remap: Option<PresetRemap>,
+    enabled: bool,
+}
+
+impl SoundFontEntry {
+    /// An enabled entry for the SoundFont at `path`, with every preset at its
+    /// own bank and number
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        SoundFontEntry {
+            path: path.into(),
+            remap: None,
+            enabled: true,
+        }
+    }
+
+    /// Only loads one preset of the SoundFont, remapped as given
+    pub fn remap(mut self, remap: PresetRemap) -> Self {
+        self.remap = Some(remap);
+        self
+    }
+
+    /// Keeps the entry in the list without loading it
+    pub fn enabled(mut self, enabled: bool) -> Self {
+        self.enabled = enabled;
+        self
+    }
+}
+
+/// Builder for an OmniMIDI `.sflist` file
+///
+/// Entries are in priority order, so presets of earlier SoundFonts are used
+/// over presets of later ones. The list can be written to a file, or loaded
+/// directly with [`KDMAPIStream::load_soundfonts`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct SoundFontList {
+    entries: Vec<SoundFontEntry>,
+}
+
+impl SoundFontList {
+    /// An empty list
+    pub fn new() -> Self {
+        SoundFontList::default()
+    }
+
+    /// Adds `entry` after the ones added before it
+    pub fn entry(mut self, entry: SoundFontEntry) -> Self {
+        self.entries.push(entry);
+        self
+    }
+
+    /// Writes the list to `path`, replacing the file if it exists
+    ///
+    /// Returns [`SoundFontError::InvalidPath`] if a SoundFont path isn't
+    /// valid Unicode or contains a line break, as the list couldn't be read
+    /// back.
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<(), SoundFontError> {
+        self.validate()?;
+        self.write_valid(File::create(path)?)
+    }
+
+    /// Same as [`SoundFontList::write`], but writes to an already open file
+    /// or other writer
+    pub fn write_to(&self, writer: impl Write) -> Result<(), SoundFontError> {
+        self.validate()?;
+        self.write_valid(writer)
+    }
+
+    fn write_valid(&self, mut writer: impl Write) -> Result<(), SoundFontError> {
+        writer.write_all(self.to_string().as_bytes())?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), SoundFontError> {
+        for entry in &self.entries {
+            match entry.path.to_str() {
+                Some(s) if !s.contains(&['\r', '\n'][..]) => {}
+                _ => return Err(SoundFontError::InvalidPath(entry.path.clone())),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes one line per entry, as `@p0,0=0,1|path` for a disabled entry with a
+/// preset remap. Paths that aren't valid Unicode are written lossily.
+impl fmt::Display for SoundFontList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for entry in &self.entries {
+            if !entry.enabled {
+                write!(f, "@")?;
+            }
+            if let Some(remap) = entry.remap {
+                write!(
+                    f,
+                    "p{},{}={},{}|",
+                    remap.source_bank,
+                    remap.source_preset,
+                    remap.destination_bank,
+                    remap.destination_preset
+                )?;
+            }
+            writeln!(f, "{}", entry.path.to_string_lossy())?;
+        }
+        Ok(())
+    }
+}
+
+impl KDMAPIStream {
+    /// Calls `LoadCustomSoundFontsList`, replacing the loaded SoundFonts with
+    /// the ones in the `.sflist` file at `path`
+    ///
+    /// Returns [`SoundFontError::Unsupported`] if the driver doesn't export
+    /// it, and an IO error if `path` isn't a readable file, as the driver
+    /// doesn't report failures.
+    pub fn load_soundfont_list(&self, path: impl AsRef<Path>) -> Result<(), SoundFontError> {
+        let load = self
+            .binds
+            .load_custom_soundfonts_list
+            .ok_or(SoundFontError::Unsupported)?;
+        let path = path.as_ref();
+        fs::File::open(path)?;
+        let wide = to_wide(path)?;
+        unsafe { load(wide.as_ptr()) };
+        Ok(())
+    }
+
+    /// Writes `list` to a temporary `.sflist` file and loads it with
+    /// [`KDMAPIStream::load_soundfont_list`]
+    pub fn load_soundfonts(&self, list: &SoundFontList) -> Result<(), SoundFontError> {
+        if self.binds.load_custom_soundfonts_list.is_none() {
+            return Err(SoundFontError::Unsupported);
+        }
+        list.validate()?;
+        let (path, file) = create_temp_list()?;
+        if let Err(e) = list.write_valid(file) {
+            let _ = fs::remove_file(&path);
+            return Err(e);
+        }
+        // The driver reads the list before returning
+        let result = self.load_soundfont_list(&path);
+        let _ = fs::remove_file(&path);
+        result
+    }
+}
+
+/// Creates a new, empty `.sflist` file in the temporary directory
+///
+/// The file must not already exist, so that a file or symlink planted at a
+/// predictable name by another user isn't written through.
+fn create_temp_list() -> io::Result<(PathBuf, File)> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    const ATTEMPTS: usize = 100;
+
+    let mut attempt = 0;
+    loop {
+        let path = std::env::temp_dir().join(format!(
+            "kdmapi-{}-{}.sflist",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists && attempt + 1 < ATTEMPTS => {
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
@@ -0,0 +1,246 @@
+use std::marker::PhantomData;
+use std::mem::size_of;
+use std::ptr;
+use std::time::{Duration, Instant};
+
+use libloading::Library;
+
+use crate::{load_optional_symbol, KDMAPIStream, SysExError};
+
+/// `MIDIERR_STILLPLAYING`, returned by `UnprepareLongData` while the driver
+/// still holds on to the buffer
+const MIDIERR_STILLPLAYING: u32 = 65;
+
+/// How long to wait for the driver to release a buffer before giving up
+const UNPREPARE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Mirror of the WinMM `MIDIHDR` struct that the long data functions take
+#[repr(C)]
+pub(crate) struct MidiHdr {
+    data: *mut u8,
+    buffer_length: u32,
+    bytes_recorded: u32,
+    user: usize,
+    flags: u32,
+    next: *mut MidiHdr,
+    reserved: usize,
+    offset: u32,
+    reserved_mmsystem: [usize; 8],
+}
+
+impl MidiHdr {
+    fn new(data: &mut [u8]) -> Self {
+        MidiHdr {
+            data: data.as_mut_ptr(),
+            buffer_length: data.len() as u32,
+            bytes_recorded: data.len() as u32,
+            user: 0,
+            flags: 0,
+            next: ptr::null_mut(),
+            reserved: 0,
+            offset: 0,
+            reserved_mmsystem: [0; 8],
+        }
+    }
+}
+
+const HEADER_SIZE: u32 = size_of::<MidiHdr>() as u32;
+
+type LongDataFn = unsafe extern "C" fn(*mut MidiHdr, u32) -> u32;
+
+/// `PrepareLongData`, `UnprepareLongData` and both `SendDirectLongData`
+/// variants. A buffer can't be sent without being prepared and must be
+/// unprepared before it is freed, so SysEx needs all of them.
+#[derive(Clone, Copy)]
+pub(crate) struct LongDataFns {
+    prepare_long_data: LongDataFn,
+    unprepare_long_data: LongDataFn,
+    send_direct_long_data: LongDataFn,
+    send_direct_long_data_no_buf: LongDataFn,
+}
+
+impl LongDataFns {
+    /// Returns `None` if any of the four is missing, which makes
+    /// [`KDMAPIStream::send_sysex`] return [`SysExError::Unsupported`]
+    pub(crate) fn load(lib: &Library) -> Option<Self> {
+        Some(LongDataFns {
+            prepare_long_data: load_optional_symbol(lib, "PrepareLongData")?,
+            unprepare_long_data: load_optional_symbol(lib, "UnprepareLongData")?,
+            send_direct_long_data: load_optional_symbol(lib, "SendDirectLongData")?,
+            send_direct_long_data_no_buf: load_optional_symbol(lib, "SendDirectLongDataNoBuf")?,
+        })
+    }
+}
+
+/// Checks that `data` is a single complete SysEx message: starts with `F0`,
+/// ends with `F7`, and has no other status bytes in between.
+pub(crate) fn validate_sysex(data: &[u8]) -> Result<(), SysExError> {
+    if data.len() < 2 || data[0] != 0xF0 || data[data.len() - 1] != 0xF7 {
+        return Err(SysExError::InvalidFraming);
+    }
+    if data.len() > u32::MAX as usize {
+        return Err(SysExError::TooLong(data.len()));
+    }
+    match data[1..data.len() - 1].iter().position(|&b| b & 0x80 != 0) {
+        Some(i) => Err(SysExError::InvalidDataByte {
+            index: i + 1,
+            byte: data[i + 1],
+        }),
+        None => Ok(()),
+    }
+}
+
+impl KDMAPIStream {
+    /// Sends a complete SysEx message (including the `F0` and `F7` bytes)
+    /// through `PrepareLongData`, `SendDirectLongData` and `UnprepareLongData`.
+    ///
+    /// The message is copied into a buffer that is kept alive until the
+    /// driver has released it, so `data` can be reused as soon as this returns.
+    /// Returns [`SysExError::Timeout`] if the driver doesn't release it within
+    /// a second.
+    ///
+    /// Returns [`SysExError::Unsupported`] if the driver doesn't export the
+    /// long data functions.
+    pub fn send_sysex(&self, data: &[u8]) -> Result<(), SysExError> {
+        self.send_sysex_async(data)?.finish()
+    }
+
+    /// Same as [`KDMAPIStream::send_sysex`], but calls
+    /// `SendDirectLongDataNoBuf`
+    pub fn send_sysex_no_buf(&self, data: &[u8]) -> Result<(), SysExError> {
+        self.send_long(
+            data,
+            |fns| fns.send_direct_long_data_no_buf,
+            "SendDirectLongDataNoBuf",
+        )?
+        .finish()
+    }
+
+    /// Same as [`KDMAPIStream::send_sysex`], but returns once the message has
+    /// been handed to the driver instead of waiting for it to release the
+    /// buffer.
+    ///
+    /// The driver reports that it is done with the buffer through
+    /// [`CallbackMessage::Done`](crate::CallbackMessage::Done), which can be
+    /// matched to the message with [`PendingSysEx::header`].
+    pub fn send_sysex_async(&self, data: &[u8]) -> Result<PendingSysEx<'_>, SysExError> {
+        self.send_long(data, |fns| fns.send_direct_long_data, "SendDirectLongData")
+    }
+
+    fn send_long(
+        &self,
+        data: &[u8],
+        send: fn(&LongDataFns) -> LongDataFn,
+        function: &'static str,
+    ) -> Result<PendingSysEx<'_>, SysExError> {
+        let fns = self.binds.long_data.ok_or(SysExError::Unsupported)?;
+        let send = send(&fns);
+        validate_sysex(data)?;
+
+        let mut buffer = data.to_vec();
+        // Boxed so that its address stays the same while the driver uses it,
+        // and so that it can be leaked along with the buffer if the driver
+        // never releases them
+        let mut header = Box::new(MidiHdr::new(&mut buffer));
+
+        let code = unsafe { (fns.prepare_long_data)(&mut *header, HEADER_SIZE) };
+        if code != 0 {
+            return Err(SysExError::Driver {
+                function: "PrepareLongData",
+                code,
+            });
+        }
+        let mut pending = PendingSysEx {
+            fns,
+            header: Some(header),
+            buffer,
+            _stream: PhantomData,
+        };
+
+        let sent = unsafe { send(pending.header_mut(), HEADER_SIZE) };
+        if sent != 0 {
+            // The header and buffer must outlive the driver's use of them, so
+            // always unprepare, even if sending failed
+            return match pending.unprepare() {
+                Err(SysExError::Timeout) => Err(SysExError::Timeout),
+                _ => Err(SysExError::Driver {
+                    function,
+                    code: sent,
+                }),
+            };
+        }
+        Ok(pending)
+    }
+}
+
+/// A SysEx message sent with [`KDMAPIStream::send_sysex_async`] that the
+/// driver may still be using
+///
+/// Waits for the driver to release the buffer when dropped, see
+/// [`PendingSysEx::finish`].
+#[must_use = "dropping this waits for the driver to release the buffer"]
+pub struct PendingSysEx<'a> {
+    fns: LongDataFns,
+    /// `None` once unprepared
+    header: Option<Box<MidiHdr>>,
+    buffer: Vec<u8>,
+    _stream: PhantomData<&'a KDMAPIStream>,
+}
+
+impl PendingSysEx<'_> {
+    /// The address of the message's `MIDIHDR`, as passed back in
+    /// [`CallbackMessage::Done`](crate::CallbackMessage::Done)
+    pub fn header(&self) -> usize {
+        self.header
+            .as_deref()
+            .map_or(0, |header| header as *const MidiHdr as usize)
+    }
+
+    /// Calls `UnprepareLongData` until the driver releases the buffer.
+    ///
+    /// Returns [`SysExError::Timeout`] if the driver doesn't release it within
+    /// a second.
+    pub fn finish(mut self) -> Result<(), SysExError> {
+        self.unprepare()
+    }
+
+    fn header_mut(&mut self) -> *mut MidiHdr {
+        self.header
+            .as_deref_mut()
+            .map_or(ptr::null_mut(), |header| header as *mut MidiHdr)
+    }
+
+    fn unprepare(&mut self) -> Result<(), SysExError> {
+        if self.header.is_none() {
+            return Ok(());
+        }
+        let deadline = Instant::now() + UNPREPARE_TIMEOUT;
+        let code = loop {
+            match unsafe { (self.fns.unprepare_long_data)(self.header_mut(), HEADER_SIZE) } {
+                MIDIERR_STILLPLAYING if Instant::now() < deadline => std::thread::yield_now(),
+                MIDIERR_STILLPLAYING => {
+                    if let Some(header) = self.header.take() {
+                        Box::leak(header);
+                    }
+                    std::mem::forget(std::mem::take(&mut self.buffer));
+                    return Err(SysExError::Timeout);
+                }
+                code => break code,
+            }
+        };
+        self.header = None;
+        if code != 0 {
+            return Err(SysExError::Driver {
+                function: "UnprepareLongData",
+                code,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PendingSysEx<'_> {
+    fn drop(&mut self) {
+        let _ = self.unprepare();
+    }
+}
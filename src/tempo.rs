@@ -0,0 +1,384 @@
+//! Conversion between ticks, time, and bars and beats
+
+use std::fmt;
+use std::time::Duration;
+
+use crate::smf::{EventKind, Fps, MetaEvent, Smf, Timing};
+
+/// The tempo in effect before the first Set Tempo event, 120 BPM
+pub(crate) const DEFAULT_TEMPO: u32 = 500_000;
+
+/// The length of a tick in microseconds, as a fraction
+///
+/// A zero division or tempo, which the parser rejects but can still be
+/// constructed, is treated as 1 so conversions never divide by zero.
+fn micros_per_tick(timing: Timing, tempo: u32) -> (u128, u128) {
+    match timing {
+        Timing::Metrical(ppq) => (tempo.max(1) as u128, ppq.max(1) as u128),
+        Timing::Timecode { fps, subframes } => {
+            let subframes = subframes.max(1) as u128;
+            match fps {
+                Fps::Fps29 => (1_000_000 * 1001, 30_000 * subframes),
+                fps => (1_000_000, fps.frames() as u128 * subframes),
+            }
+        }
+    }
+}
+
+/// Converts between ticks and microseconds while walking a file's events in
+/// order. Times are always computed from the last tempo change rather than
+/// accumulated per event, so rounding errors don't build up.
+#[derive(Debug, Clone)]
+pub(crate) struct TempoCursor {
+    timing: Timing,
+    tick: u64,
+    micros: u64,
+    tempo: u32,
+    num: u128,
+    den: u128,
+}
+
+impl TempoCursor {
+    pub(crate) fn new(timing: Timing) -> Self {
+        let (num, den) = micros_per_tick(timing, DEFAULT_TEMPO);
+        TempoCursor {
+            timing,
+            tick: 0,
+            micros: 0,
+            tempo: DEFAULT_TEMPO,
+            num,
+            den,
+        }
+    }
+
+    /// The time of `tick`, which must not be before the last tempo change
+    pub(crate) fn micros_at(&self, tick: u64) -> u64 {
+        let ticks = tick.saturating_sub(self.tick) as u128;
+        self.micros + (ticks * self.num / self.den) as u64
+    }
+
+    /// The time of `tick`, or `None` if it's before the last tempo change
+    pub(crate) fn checked_micros_at(&self, tick: u64) -> Option<u64> {
+        (tick >= self.tick).then(|| self.micros_at(tick))
+    }
+
+    /// The tick at `micros`, which must not be before the last tempo change
+    pub(crate) fn tick_at(&self, micros: u64) -> u64 {
+        let micros = micros.saturating_sub(self.micros) as u128;
+        self.tick + (micros * self.den / self.num) as u64
+    }
+
+    /// Applies a Set Tempo event at `tick`. Ignored for timecode divisions,
+    /// where ticks have a fixed length.
+    pub(crate) fn set_tempo(&mut self, tick: u64, tempo: u32) {
+        if let Timing::Metrical(_) = self.timing {
+            self.micros = self.micros_at(tick);
+            self.tick = tick;
+            self.tempo = tempo.max(1);
+            let (num, den) = micros_per_tick(self.timing, self.tempo);
+            self.num = num;
+            self.den = den;
+        }
+    }
+}
+
+/// A position in bars and beats, counted from 0
+///
+/// Displayed counting from 1, as `bar.beat.tick`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BarBeat {
+    pub bar: u64,
+    pub beat: u64,
+    /// Ticks into the beat
+    pub tick: u64,
+}
+
+impl fmt::Display for BarBeat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{:03}", self.bar + 1, self.beat + 1, self.tick)
+    }
+}
+
+/// A time signature and the bar it starts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Signature {
+    tick: u64,
+    bar: u64,
+    numerator: u8,
+    /// As a power of two
+    denominator: u8,
+}
+
+impl Signature {
+    fn beat_ticks(&self, ppq: u16) -> u64 {
+        // Scaled up first so that denominators above 4 don't truncate
+        (((ppq as u64) * 4) >> self.denominator.min(63)).max(1)
+    }
+
+    fn bar_ticks(&self, ppq: u16) -> u64 {
+        self.beat_ticks(ppq) * self.numerator.max(1) as u64
+    }
+}
+
+/// The tempo and time signature changes of a file, for converting between
+/// ticks, time, and bars and beats in O(log n)
+///
+/// With a timecode division ticks have a fixed length, so tempo changes are
+/// ignored and positions can't be expressed in bars and beats.
+#[derive(Debug, Clone)]
+pub struct TempoMap {
+    timing: Timing,
+    /// The tempo in effect from each change onwards, sorted by tick. The
+    /// first entry is always at tick 0.
+    tempos: Vec<TempoCursor>,
+    /// Sorted by tick, the first entry is always at tick 0
+    signatures: Vec<Signature>,
+}
+
+impl TempoMap {
+    /// A map with the default tempo of 120 BPM and a 4/4 time signature
+    pub fn new(timing: Timing) -> Self {
+        TempoMap {
+            timing,
+            tempos: vec![TempoCursor::new(timing)],
+            signatures: vec![Signature {
+                tick: 0,
+                bar: 0,
+                numerator: 4,
+                denominator: 2,
+            }],
+        }
+    }
+
+    /// Builds the map from every Set Tempo and Time Signature event in `smf`
+    pub fn from_smf(smf: &Smf) -> Self {
+        let mut events = Vec::new();
+        smf.for_each_timed(|tick, kind| {
+            if let EventKind::Meta(meta) = kind {
+                events.push((tick, meta.clone()));
+            }
+        });
+        TempoMap::from_events(smf.header.timing, events)
+    }
+
+    /// Builds the map from meta events and their absolute ticks. Events other
+    /// than Set Tempo and Time Signature are ignored.
+    pub fn from_events<I>(timing: Timing, events: I) -> Self
+    where
+        I: IntoIterator<Item = (u64, MetaEvent)>,
+    {
+        let mut events: Vec<_> = events
+            .into_iter()
+            .filter(|(_, meta)| {
+                matches!(meta, MetaEvent::Tempo(_) | MetaEvent::TimeSignature { .. })
+            })
+            .collect();
+        // Stable, so the last of several changes at the same tick wins
+        events.sort_by_key(|(tick, _)| *tick);
+
+        let mut map = TempoMap::new(timing);
+        for (tick, meta) in events {
+            match meta {
+                MetaEvent::Tempo(tempo) => map.push_tempo(tick, tempo),
+                MetaEvent::TimeSignature {
+                    numerator,
+                    denominator,
+                    ..
+                } => map.push_signature(tick, numerator, denominator),
+                _ => {}
+            }
+        }
+        map
+    }
+
+    fn push_tempo(&mut self, tick: u64, tempo: u32) {
+        if let Timing::Timecode { .. } = self.timing {
+            return;
+        }
+        let last = self.tempos.len() - 1;
+        let mut cursor = self.tempos[last].clone();
+        cursor.set_tempo(tick, tempo);
+        if self.tempos[last].tick == tick {
+            self.tempos[last] = cursor;
+        } else {
+            self.tempos.push(cursor);
+        }
+    }
+
+    fn push_signature(&mut self, tick: u64, numerator: u8, denominator: u8) {
+        let ppq = match self.timing {
+            Timing::Metrical(ppq) => ppq,
+            Timing::Timecode { .. } => return,
+        };
+        let last = self.signatures[self.signatures.len() - 1];
+        // A signature change always starts a new bar, even in the middle of one
+        let (elapsed, bar_ticks) = (tick - last.tick, last.bar_ticks(ppq));
+        let bar = last.bar + elapsed / bar_ticks + (elapsed % bar_ticks != 0) as u64;
+        let signature = Signature {
+            tick,
+            bar,
+            numerator,
+            denominator,
+        };
+        if last.tick == tick {
+            let last = self.signatures.len() - 1;
+            self.signatures[last] = signature;
+        } else {
+            self.signatures.push(signature);
+        }
+    }
+
+    pub fn timing(&self) -> Timing {
+        self.timing
+    }
+
+    /// The tempo at `tick`, in microseconds per quarter note
+    pub fn tempo_at(&self, tick: u64) -> u32 {
+        self.tempo_segment(tick).tempo
+    }
+
+    /// The time signature at `tick`, as the numerator and the denominator as
+    /// a power of two
+    pub fn time_signature_at(&self, tick: u64) -> (u8, u8) {
+        let signature = self.signature_segment(tick);
+        (signature.numerator, signature.denominator)
+    }
+
+    /// The time of `tick` in microseconds
+    pub fn tick_to_micros(&self, tick: u64) -> u64 {
+        self.tempo_segment(tick).micros_at(tick)
+    }
+
+    /// The tick at `micros`, rounded down
+    pub fn micros_to_tick(&self, micros: u64) -> u64 {
+        let index = self.tempos.partition_point(|t| t.micros <= micros);
+        self.tempos[index.saturating_sub(1)].tick_at(micros)
+    }
+
+    /// The time of `tick`
+    pub fn tick_to_time(&self, tick: u64) -> Duration {
+        Duration::from_micros(self.tick_to_micros(tick))
+    }
+
+    /// The tick at `time`, rounded down
+    pub fn time_to_tick(&self, time: Duration) -> u64 {
+        self.micros_to_tick(time.as_micros().min(u64::MAX as u128) as u64)
+    }
+
+    /// The bar and beat of `tick`, `None` for timecode divisions
+    pub fn tick_to_bar_beat(&self, tick: u64) -> Option<BarBeat> {
+        let ppq = self.ppq()?;
+        let signature = self.signature_segment(tick);
+        let ticks = tick - signature.tick;
+        let bar_ticks = signature.bar_ticks(ppq);
+        let beat_ticks = signature.beat_ticks(ppq);
+        Some(BarBeat {
+            bar: signature.bar + ticks / bar_ticks,
+            beat: ticks % bar_ticks / beat_ticks,
+            tick: ticks % bar_ticks % beat_ticks,
+        })
+    }
+
+    /// The tick of a bar and beat position, `None` for timecode divisions
+    pub fn bar_beat_to_tick(&self, position: BarBeat) -> Option<u64> {
+        let ppq = self.ppq()?;
+        let index = self.signatures.partition_point(|s| s.bar <= position.bar);
+        let signature = self.signatures[index.saturating_sub(1)];
+        Some(
+            signature.tick
+                + (position.bar - signature.bar) * signature.bar_ticks(ppq)
+                + position.beat * signature.beat_ticks(ppq)
+                + position.tick,
+        )
+    }
+
+    fn ppq(&self) -> Option<u16> {
+        match self.timing {
+            Timing::Metrical(ppq) => Some(ppq),
+            Timing::Timecode { .. } => None,
+        }
+    }
+
+    fn tempo_segment(&self, tick: u64) -> &TempoCursor {
+        let index = self.tempos.partition_point(|t| t.tick <= tick);
+        &self.tempos[index.saturating_sub(1)]
+    }
+
+    fn signature_segment(&self, tick: u64) -> Signature {
+        let index = self.signatures.partition_point(|s| s.tick <= tick);
+        self.signatures[index.saturating_sub(1)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time_signature(numerator: u8, denominator: u8) -> MetaEvent {
+        MetaEvent::TimeSignature {
+            numerator,
+            denominator,
+            clocks_per_click: 24,
+            thirty_seconds_per_quarter: 8,
+        }
+    }
+
+    #[test]
+    fn converts_across_tempo_changes() {
+        let map = TempoMap::from_events(
+            Timing::Metrical(96),
+            vec![
+                (192, MetaEvent::Tempo(250_000)),
+                (0, MetaEvent::Tempo(1_000_000)),
+            ],
+        );
+        assert_eq!(map.tick_to_micros(96), 1_000_000);
+        assert_eq!(map.tick_to_micros(192), 2_000_000);
+        assert_eq!(map.tick_to_micros(288), 2_250_000);
+        assert_eq!(map.micros_to_tick(2_250_000), 288);
+        assert_eq!(map.micros_to_tick(1_500_000), 144);
+        assert_eq!(map.tempo_at(191), 1_000_000);
+        assert_eq!(map.tempo_at(192), 250_000);
+    }
+
+    #[test]
+    fn ignores_tempo_with_smpte_division() {
+        let timing = Timing::Timecode {
+            fps: Fps::Fps25,
+            subframes: 40,
+        };
+        let map = TempoMap::from_events(timing, vec![(0, MetaEvent::Tempo(1_000_000))]);
+        assert_eq!(map.tick_to_micros(1000), 1_000_000);
+        assert_eq!(map.micros_to_tick(500_000), 500);
+        assert_eq!(map.tick_to_bar_beat(1000), None);
+    }
+
+    #[test]
+    fn zero_division_does_not_panic() {
+        let map = TempoMap::from_events(Timing::Metrical(0), vec![(0, MetaEvent::Tempo(0))]);
+        assert_eq!(map.tick_to_micros(10), 10);
+        assert_eq!(map.micros_to_tick(10), 10);
+
+        let timing = Timing::Timecode {
+            fps: Fps::Fps30,
+            subframes: 0,
+        };
+        assert_eq!(TempoMap::new(timing).tick_to_micros(30), 1_000_000);
+    }
+
+    #[test]
+    fn counts_bars_across_time_signatures() {
+        let map = TempoMap::from_events(
+            Timing::Metrical(96),
+            vec![(0, time_signature(3, 2)), (96 * 6, time_signature(6, 3))],
+        );
+        let position = BarBeat {
+            bar: 2,
+            beat: 3,
+            tick: 10,
+        };
+        assert_eq!(map.bar_beat_to_tick(position), Some(96 * 6 + 48 * 3 + 10));
+        assert_eq!(map.tick_to_bar_beat(96 * 6 + 48 * 3 + 10), Some(position));
+        assert_eq!(map.tick_to_bar_beat(96 * 4).unwrap().to_string(), "2.2.000");
+    }
+}
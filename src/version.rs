@@ -0,0 +1,59 @@
+use std::fmt;
+
+use crate::KDMAPIBinds;
+
+/// The KDMAPI version reported by `ReturnKDMAPIVer`
+///
+/// Versions are ordered by major, minor, build, then revision.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct KdmapiVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub build: u32,
+    pub revision: u32,
+}
+
+impl KdmapiVersion {
+    pub const fn new(major: u32, minor: u32, build: u32, revision: u32) -> Self {
+        KdmapiVersion {
+            major,
+            minor,
+            build,
+            revision,
+        }
+    }
+}
+
+impl fmt::Display for KdmapiVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{}.{}.{}",
+            self.major, self.minor, self.build, self.revision
+        )
+    }
+}
+
+impl KDMAPIBinds {
+    /// Calls `ReturnKDMAPIVer`
+    ///
+    /// Returns `None` if the driver is too old to export it, or if the call
+    /// fails.
+    pub fn version(&self) -> Option<KdmapiVersion> {
+        let return_kdmapi_ver = self.return_kdmapi_ver?;
+        let mut version = KdmapiVersion::new(0, 0, 0, 0);
+        let ok = unsafe {
+            return_kdmapi_ver(
+                &mut version.major,
+                &mut version.minor,
+                &mut version.build,
+                &mut version.revision,
+            )
+        };
+        if ok {
+            Some(version)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,428 @@
+//! Loads the `omnimidi-stub` fixture through the real dynamic loading path
+//! and checks the calls it logs.
+
+use std::env::consts::{DLL_PREFIX, DLL_SUFFIX};
+use std::ffi::OsStr;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use kdmapi::{
+    CallbackError, CallbackMessage, Capabilities, Clock, DebugInfo, DriverSetting,
+    DriverSettingError, KDMAPILoader, KdmapiError, KdmapiVersion, MidiMessage, PresetRemap,
+    SoundFontEntry, SoundFontList, StreamError, U4, U7,
+};
+
+// The stub is configured through environment variables and the loaded
+// library is shared by the whole process, so tests must not overlap
+static LOCK: Mutex<()> = Mutex::new(());
+
+struct StubLog {
+    path: PathBuf,
+    _guard: MutexGuard<'static, ()>,
+}
+
+impl StubLog {
+    fn new(name: &str) -> Self {
+        let guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let path =
+            std::env::temp_dir().join(format!("kdmapi-stub-{}-{}.log", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        std::env::set_var("OMNIMIDI_STUB_LOG", &path);
+        StubLog {
+            path,
+            _guard: guard,
+        }
+    }
+
+    fn lines(&self) -> Vec<String> {
+        std::fs::read_to_string(&self.path)
+            .unwrap_or_default()
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+}
+
+impl Drop for StubLog {
+    fn drop(&mut self) {
+        std::env::remove_var("OMNIMIDI_STUB_LOG");
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// The stub is a dev-dependency, so cargo builds it next to the test binary
+fn stub_path() -> PathBuf {
+    let exe = std::env::current_exe().unwrap();
+    exe.parent()
+        .unwrap()
+        .join(format!("{}OmniMIDI{}", DLL_PREFIX, DLL_SUFFIX))
+}
+
+fn stub_loader() -> KDMAPILoader {
+    KDMAPILoader::new()
+        .no_env_var()
+        .names(Vec::<String>::new())
+        .path(stub_path())
+}
+
+#[test]
+fn loads_from_explicit_path() {
+    let _log = StubLog::new("explicit-path");
+    let binds = stub_loader().load().unwrap();
+
+    assert_eq!(binds.library_name(), stub_path().as_os_str());
+    assert!(binds.is_kdmapi_available());
+    assert!(binds
+        .capabilities()
+        .contains(Capabilities::LONG_DATA | Capabilities::VERSION | Capabilities::DRIVER_SETTINGS));
+    assert_eq!(binds.version(), Some(KdmapiVersion::new(4, 1, 2, 3)));
+}
+
+#[test]
+fn loads_from_env_var() {
+    let _log = StubLog::new("env-var");
+    std::env::set_var("KDMAPI_STUB_TEST_LIBRARY", stub_path());
+    let result = KDMAPILoader::new()
+        .env_var("KDMAPI_STUB_TEST_LIBRARY")
+        .names(vec!["kdmapi-missing-library"])
+        .load();
+    std::env::remove_var("KDMAPI_STUB_TEST_LIBRARY");
+
+    assert_eq!(result.unwrap().library_name(), stub_path().as_os_str());
+}
+
+#[test]
+fn env_var_does_not_fall_back() {
+    let _log = StubLog::new("env-var-missing");
+    std::env::set_var("KDMAPI_STUB_TEST_MISSING", "kdmapi-missing-override");
+    let result = KDMAPILoader::new()
+        .env_var("KDMAPI_STUB_TEST_MISSING")
+        .path(stub_path())
+        .load();
+    std::env::remove_var("KDMAPI_STUB_TEST_MISSING");
+
+    match result {
+        Err(KdmapiError::LibraryNotFound(attempts)) => {
+            let names: Vec<_> = attempts.iter().map(|(name, _)| name.as_os_str()).collect();
+            assert_eq!(names, [OsStr::new("kdmapi-missing-override")]);
+        }
+        _ => panic!("expected LibraryNotFound"),
+    }
+}
+
+#[test]
+fn reports_every_missing_candidate() {
+    let _log = StubLog::new("missing");
+    let result = KDMAPILoader::new()
+        .no_env_var()
+        .names(vec!["kdmapi-missing-a", "kdmapi-missing-b"])
+        .load();
+
+    match result {
+        Err(KdmapiError::LibraryNotFound(attempts)) => {
+            let names: Vec<&OsStr> = attempts.iter().map(|(name, _)| name.as_os_str()).collect();
+            assert_eq!(names, vec!["kdmapi-missing-a", "kdmapi-missing-b"]);
+        }
+        _ => panic!("expected LibraryNotFound"),
+    }
+}
+
+#[test]
+fn stream_calls_reach_the_library() {
+    let log = StubLog::new("stream");
+    let binds = stub_loader().load().unwrap();
+
+    let stream = binds.try_open_stream().unwrap();
+    assert!(matches!(
+        binds.try_open_stream(),
+        Err(StreamError::AlreadyOpen)
+    ));
+    stream.send_direct_data(0x7F4090);
+    stream.send_no_buf(MidiMessage::NoteOff {
+        channel: U4::new(0).unwrap(),
+        key: U7::new(0x40).unwrap(),
+        velocity: U7::new(0).unwrap(),
+    });
+    stream
+        .send_sysex(&[0xF0, 0x7E, 0x7F, 0x09, 0x01, 0xF7])
+        .unwrap();
+    stream.reset();
+    drop(stream);
+
+    assert_eq!(
+        log.lines(),
+        vec![
+            "InitializeKDMAPIStream",
+            "SendDirectData 007f4090",
+            "SendDirectDataNoBuf 00004080",
+            "PrepareLongData",
+            "SendDirectLongData f07e7f0901f7",
+            "UnprepareLongData",
+            "ResetKDMAPIStream",
+            "TerminateKDMAPIStream",
+        ]
+    );
+}
+
+#[test]
+fn failed_init_can_be_retried() {
+    let log = StubLog::new("init-failure");
+    let binds = stub_loader().load().unwrap();
+
+    std::env::set_var("OMNIMIDI_STUB_FAIL_INIT", "1");
+    let result = binds.try_open_stream();
+    std::env::remove_var("OMNIMIDI_STUB_FAIL_INIT");
+    assert!(matches!(result, Err(StreamError::InitFailed)));
+
+    drop(binds.try_open_stream().unwrap());
+    assert_eq!(
+        log.lines(),
+        vec![
+            "InitializeKDMAPIStream",
+            "InitializeKDMAPIStream",
+            "TerminateKDMAPIStream",
+        ]
+    );
+}
+
+#[test]
+fn driver_settings_are_validated() {
+    let log = StubLog::new("settings");
+    let binds = stub_loader().load().unwrap();
+    let stream = binds.try_open_stream().unwrap();
+    let settings = stream.settings();
+
+    settings.set(DriverSetting::MaxVoices, 5000).unwrap();
+    assert_eq!(settings.get(DriverSetting::MaxVoices), Ok(5000));
+    assert_eq!(
+        settings.set(DriverSetting::MaxVoices, 0),
+        Err(DriverSettingError::OutOfRange {
+            setting: DriverSetting::MaxVoices,
+            value: 0
+        })
+    );
+    settings
+        .set_enabled(DriverSetting::IgnoreSysReset, true)
+        .unwrap();
+    drop(stream);
+
+    assert_eq!(
+        log.lines(),
+        vec![
+            "InitializeKDMAPIStream",
+            "DriverSettings set 10026 5000",
+            "DriverSettings get 10026",
+            "DriverSettings set 10009 1",
+            "TerminateKDMAPIStream",
+        ]
+    );
+}
+
+#[test]
+fn debug_info_is_polled() {
+    let _log = StubLog::new("debug-info");
+    let binds = stub_loader().load().unwrap();
+    let stream = binds.try_open_stream().unwrap();
+
+    let info = stream.debug_info().unwrap();
+    assert_eq!(info.rendering_time, 12.5);
+    assert_eq!(info.total_voices(), 120);
+
+    let (sender, receiver) = mpsc::channel();
+    let poller = stream
+        .poll_debug_info(Duration::from_millis(5), move |info| {
+            let _ = sender.send(info);
+        })
+        .unwrap();
+    for _ in 0..3 {
+        let sample: DebugInfo = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(sample, info);
+    }
+    drop(poller);
+    assert!(receiver.recv().is_err());
+}
+
+#[test]
+fn debug_info_polling_is_rate_limited() {
+    let _log = StubLog::new("debug-info-zero");
+    let binds = stub_loader().load().unwrap();
+    let stream = binds.try_open_stream().unwrap();
+
+    let (sender, receiver) = mpsc::channel();
+    let poller = stream
+        .poll_debug_info(Duration::ZERO, move |_| {
+            let _ = sender.send(());
+        })
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(50));
+    drop(poller);
+    // Roughly one sample per millisecond rather than a busy loop
+    assert!(receiver.try_iter().count() <= 100);
+}
+
+#[test]
+fn soundfont_list_is_written_and_loaded() {
+    let log = StubLog::new("soundfonts");
+    let binds = stub_loader().load().unwrap();
+    let stream = binds.try_open_stream().unwrap();
+
+    let list = SoundFontList::new()
+        .entry(SoundFontEntry::new("/sf/piano.sf2").remap(PresetRemap::new(0, 0, 0, 1)))
+        .entry(SoundFontEntry::new("/sf/gm.sf2"))
+        .entry(SoundFontEntry::new("/sf/old.sf2").enabled(false));
+    stream.load_soundfonts(&list).unwrap();
+    drop(stream);
+
+    let lines = log.lines();
+    assert!(lines[1].starts_with("LoadCustomSoundFontsList "));
+    assert!(lines[1].ends_with(".sflist"));
+    assert_eq!(
+        lines[2..],
+        [
+            "  p0,0=0,1|/sf/piano.sf2",
+            "  /sf/gm.sf2",
+            "  @/sf/old.sf2",
+            "TerminateKDMAPIStream",
+        ]
+    );
+}
+
+#[test]
+fn soundfont_list_does_not_overwrite_existing_files() {
+    let log = StubLog::new("soundfont-existing");
+    let binds = stub_loader().load().unwrap();
+    let stream = binds.try_open_stream().unwrap();
+
+    // Files already at the names the temporary list could be given
+    let planted: Vec<_> = (0..10)
+        .map(|i| {
+            let path =
+                std::env::temp_dir().join(format!("kdmapi-{}-{}.sflist", std::process::id(), i));
+            std::fs::write(&path, "planted").unwrap();
+            path
+        })
+        .collect();
+    let list = SoundFontList::new().entry(SoundFontEntry::new("/sf/gm.sf2"));
+    let result = stream.load_soundfonts(&list);
+    let contents: Vec<_> = planted
+        .iter()
+        .map(|path| std::fs::read_to_string(path).unwrap())
+        .collect();
+    for path in &planted {
+        let _ = std::fs::remove_file(path);
+    }
+    drop(stream);
+
+    result.unwrap();
+    assert!(contents.iter().all(|contents| contents == "planted"));
+    assert!(log.lines().contains(&"  /sf/gm.sf2".to_string()));
+}
+
+#[test]
+fn driver_clock_advances() {
+    let _log = StubLog::new("clock");
+    let binds = stub_loader().load().unwrap();
+    assert!(binds.capabilities().contains(Capabilities::TIMER));
+
+    let clock = binds.driver_clock().unwrap();
+    let start = clock.now();
+    std::thread::sleep(Duration::from_millis(20));
+    let elapsed = clock.now() - start;
+    assert!(elapsed >= Duration::from_millis(19));
+}
+
+#[test]
+fn callbacks_report_finished_buffers() {
+    let log = StubLog::new("callbacks");
+    let binds = stub_loader().load().unwrap();
+    let stream = binds.try_open_stream().unwrap();
+
+    let (registration, receiver) = binds.callback_channel().unwrap();
+    binds.run_callback(CallbackMessage::Open).unwrap();
+    stream
+        .send_sysex(&[0xF0, 0x7E, 0x7F, 0x09, 0x01, 0xF7])
+        .unwrap();
+    assert_eq!(receiver.try_recv(), Ok(CallbackMessage::Open));
+    assert!(matches!(
+        receiver.try_recv(),
+        Ok(CallbackMessage::Done { header }) if header != 0
+    ));
+
+    drop(registration);
+    binds.run_callback(CallbackMessage::Close).unwrap();
+    assert!(receiver.try_recv().is_err());
+    drop(stream);
+
+    let lines = log.lines();
+    assert_eq!(lines[1], "InitializeCallbackFeatures 30000");
+    assert_eq!(lines[lines.len() - 2], "InitializeCallbackFeatures 0");
+}
+
+#[test]
+fn rejected_callback_keeps_the_previous_one() {
+    let _log = StubLog::new("callback-failure");
+    let binds = stub_loader().load().unwrap();
+    let (_registration, receiver) = binds.callback_channel().unwrap();
+
+    std::env::set_var("OMNIMIDI_STUB_FAIL_CALLBACK", "1");
+    let result = binds.set_callback(|_| panic!("replaced the previous callback"));
+    std::env::remove_var("OMNIMIDI_STUB_FAIL_CALLBACK");
+    assert!(matches!(result, Err(CallbackError::InitFailed)));
+
+    binds.run_callback(CallbackMessage::Open).unwrap();
+    assert_eq!(receiver.try_recv(), Ok(CallbackMessage::Open));
+}
+
+#[test]
+fn finished_buffers_match_async_sends() {
+    let log = StubLog::new("sysex-async");
+    let binds = stub_loader().load().unwrap();
+    let stream = binds.try_open_stream().unwrap();
+    let (_registration, receiver) = binds.callback_channel().unwrap();
+
+    let first = stream
+        .send_sysex_async(&[0xF0, 0x7E, 0x7F, 0x09, 0x01, 0xF7])
+        .unwrap();
+    let second = stream
+        .send_sysex_async(&[0xF0, 0x7E, 0x7F, 0x09, 0x02, 0xF7])
+        .unwrap();
+    assert_ne!(first.header(), second.header());
+    for pending in [&first, &second] {
+        assert_eq!(
+            receiver.try_recv(),
+            Ok(CallbackMessage::Done {
+                header: pending.header()
+            })
+        );
+    }
+    second.finish().unwrap();
+    drop(first);
+    drop(stream);
+
+    let lines = log.lines();
+    assert_eq!(
+        lines[lines.len() - 3..],
+        [
+            "UnprepareLongData",
+            "UnprepareLongData",
+            "TerminateKDMAPIStream"
+        ]
+    );
+}
+
+#[test]
+fn separately_loaded_binds_share_the_stream() {
+    let _log = StubLog::new("shared-stream");
+    let first = stub_loader().load().unwrap();
+    let second = stub_loader().load().unwrap();
+
+    let stream = first.try_open_stream().unwrap();
+    assert!(matches!(
+        second.try_open_stream(),
+        Err(StreamError::AlreadyOpen)
+    ));
+    drop(stream);
+    assert!(second.try_open_stream().is_ok());
+}